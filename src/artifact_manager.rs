@@ -1,103 +1,1721 @@
 use crate::error::ArtifactError;
+use crate::job::{ArtifactFormat, ArtifactsConfig, Variable};
+use crate::reports::parse_dotenv;
 
-use std::fs;
-use std::path::Path;
+use std::collections::HashSet;
+use std::fs::{self, File, FileTimes};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use fs_utils::copy;
-use glob::glob;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use glob::{MatchOptions, Pattern, glob};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+const BLOBS_DIR: &str = "blobs";
+const RUNS_DIR: &str = "runs";
+const RESULTS_DIR: &str = "results";
+const RESULT_LOG: &str = "job.log";
+const RESULT_REPORTS: &str = "reports";
+const MANIFEST_FILE: &str = "manifest.json";
+const DOTENV_FILE: &str = "dotenv.env";
+// Unreferenced blobs younger than this are kept by `prune`, since a job that is
+// still saving its artifacts has stored its blobs but not written its manifest yet
+const BLOB_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+// Results not restored for this long are deleted by `prune`
+const RESULT_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ManifestEntry {
+    File {
+        path: PathBuf,
+        hash: String,
+        size: u64,
+        mode: u32,
+        mtime: Option<SystemTime>,
+    },
+    Dir {
+        path: PathBuf,
+        mode: u32,
+        mtime: Option<SystemTime>,
+    },
+    Symlink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+}
+
+// Lists everything a job saved. File contents live in the blob store under
+// their sha256, so identical files are only stored once across all jobs
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    // The directory is only named after the job, listing runs needs the name
+    #[serde(default)]
+    job: String,
+    format: ArtifactFormat,
+    entries: Vec<ManifestEntry>,
+    // Archive formats store everything in a single archive next to the manifest
+    // instead of listing entries
+    archive_hash: Option<String>,
+    #[serde(default)]
+    expires_at: Option<SystemTime>,
+}
+
+impl ManifestEntry {
+    fn path(&self) -> &Path {
+        match self {
+            ManifestEntry::File { path, .. }
+            | ManifestEntry::Dir { path, .. }
+            | ManifestEntry::Symlink { path, .. } => path,
+        }
+    }
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl Manifest {
+    // Manifests are read from disk, so nothing in them can be trusted. Hashes
+    // name blob paths and entry paths are joined onto the destination, so both
+    // have to stay within their directory
+    fn validate(&self) -> Result<(), String> {
+        if let Some(hash) = self.archive_hash.as_ref().filter(|hash| !is_sha256(hash)) {
+            return Err(format!("invalid archive hash {}", hash));
+        }
+        for entry in self.entries.iter() {
+            let path = entry.path();
+            if path.as_os_str().is_empty()
+                || !path
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(format!("invalid entry path {}", path.display()));
+            }
+            if let ManifestEntry::File { hash, .. } = entry
+                && !is_sha256(hash)
+            {
+                return Err(format!("invalid hash {} of {}", hash, path.display()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// What a job with `cache_result` left behind besides its artifacts. Reports are
+// read from the result, since the workspace may have changed since
+#[derive(Debug, PartialEq)]
+pub struct CachedResult {
+    pub log: String,
+    pub reports: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PruneSummary {
+    pub artifacts: usize,
+    pub results: usize,
+    pub blobs: usize,
+    pub freed: u64,
+}
+
+// Artifacts of every pipeline run are kept apart so concurrent runs on the same
+// host don't overwrite each other. The blob store is shared between runs
 #[derive(Clone)]
 pub struct ArtifactManager {
     pub workspace: String,
     pub root_dir: String,
+    pub run_id: String,
+}
+
+// Sorts by start time, and the pid keeps runs started in the same second apart
+pub fn new_run_id() -> String {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{}-{}", secs, std::process::id())
+}
+
+// Turns a job name into one that only uses [a-zA-Z0-9_.-], as docker requires
+// for container names and which is also safe as a directory name. Names like
+// `a/b` and `a b` would end up the same, so a hash of the original name keeps
+// them apart
+pub fn unique_name(name: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    let sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("{}-{}", sanitized, &hash[..8])
 }
 
 impl ArtifactManager {
-    pub fn new_with_params(workspace: String, root_dir: String) -> Self {
+    pub fn new_with_params(workspace: String, root_dir: String, run_id: String) -> Self {
         Self {
             workspace,
             root_dir,
+            run_id,
+        }
+    }
+
+    // Parallel jobs are named like `job 1/2`, so job names can't be used as is
+    fn get_job_dir_name(job_name: &str) -> String {
+        unique_name(job_name)
+    }
+
+    fn get_run_dir(&self) -> PathBuf {
+        Path::new(self.root_dir.as_str())
+            .join(RUNS_DIR)
+            .join(self.run_id.as_str())
+    }
+
+    fn get_artifact_dir_for_job(&self, job_name: &str) -> PathBuf {
+        self.get_run_dir().join(Self::get_job_dir_name(job_name))
+    }
+
+    // Blobs are spread over subdirectories named after the first two characters
+    // of the hash to keep directories small
+    fn get_blob_path(&self, hash: &str) -> PathBuf {
+        Path::new(self.root_dir.as_str())
+            .join(BLOBS_DIR)
+            .join(&hash[..2])
+            .join(hash)
+    }
+
+    // A path is also excluded if any of the directories it is in are
+    fn is_excluded(path: &Path, exclude: &[Pattern]) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        path.ancestors()
+            .any(|path| exclude.iter().any(|p| p.matches_path_with(path, options)))
+    }
+
+    fn copy_error(path: &Path, e: std::io::Error) -> ArtifactError {
+        ArtifactError::ArtifactCopyError(format!("{}: {}", path.display(), e))
+    }
+
+    // Removes a file or symlink at `dest` so it can be replaced. This also takes
+    // care of read only files left behind by an earlier load
+    fn remove_existing(dest: &Path) -> Result<(), ArtifactError> {
+        if let Ok(metadata) = fs::symlink_metadata(dest)
+            && !metadata.is_dir()
+        {
+            fs::remove_file(dest).map_err(|e| Self::copy_error(dest, e))?;
         }
+
+        Ok(())
     }
 
-    fn get_artifact_dir_for_job(&self, job_name: &str) -> String {
-        format!("{}/{}/{}", self.root_dir, self.workspace, job_name)
+    fn set_metadata(
+        dest: &Path,
+        mode: u32,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), ArtifactError> {
+        if let Some(mtime) = mtime {
+            File::open(dest)
+                .and_then(|f| f.set_times(FileTimes::new().set_modified(mtime)))
+                .map_err(|e| Self::copy_error(dest, e))?;
+        }
+        fs::set_permissions(dest, fs::Permissions::from_mode(mode))
+            .map_err(|e| Self::copy_error(dest, e))
     }
 
-    // Utility function to support copying both files and directories
-    // TODO: Copy file
-    fn copy_path(src: &str, dest: &str) -> Result<(), ArtifactError> {
-        let src_path = Path::new(src);
-        if src_path.is_dir() {
-            if fs::exists(dest).is_ok() {
-                let _ = fs::remove_dir_all(dest);
-                println!("Destination already exists");
+    // Whether a symlink at `rel_path` pointing to `target` resolves to something
+    // inside the root it is relative to. Absolute targets never do, since the
+    // workspace is mounted at a different path inside the container
+    fn is_within_root(rel_path: &Path, target: &Path) -> bool {
+        let mut depth = rel_path.components().count().saturating_sub(1);
+        for component in target.components() {
+            match component {
+                Component::Normal(_) => depth += 1,
+                Component::CurDir => {}
+                Component::ParentDir if depth > 0 => depth -= 1,
+                _ => return false,
             }
-            let _path = copy::copy_directory(src_path, dest)
-                .map_err(|e| ArtifactError::ArtifactCopyError(e.to_string()))?;
-        } else {
-            // TODO: Copy file
+        }
+
+        true
+    }
+
+    fn check_symlink(rel_path: &Path, target: &Path) -> Result<(), ArtifactError> {
+        if !Self::is_within_root(rel_path, target) {
+            return Err(ArtifactError::ArtifactCopyError(format!(
+                "{}: symlink to {} points outside of the workspace",
+                rel_path.display(),
+                target.display()
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Copies `src` into the blob store while hashing it, so the stored blob
+    // always matches its name even if `src` changes while being read. The blob is
+    // written to a temporary file first and renamed into place, which makes
+    // concurrent saves of the same content safe
+    fn store_blob(&self, src: &Path) -> Result<String, ArtifactError> {
+        let blobs_dir = Path::new(self.root_dir.as_str()).join(BLOBS_DIR);
+        fs::create_dir_all(&blobs_dir).map_err(|e| Self::copy_error(&blobs_dir, e))?;
+        let tmp_path = blobs_dir.join(format!(
+            ".tmp-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+
+        let mut src_file = File::open(src).map_err(|e| Self::copy_error(src, e))?;
+        let mut tmp_file = File::create(&tmp_path).map_err(|e| Self::copy_error(&tmp_path, e))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = src_file
+                .read(&mut buf)
+                .map_err(|e| Self::copy_error(src, e))?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+            tmp_file
+                .write_all(&buf[..len])
+                .map_err(|e| Self::copy_error(&tmp_path, e))?;
+        }
+        drop(tmp_file);
+
+        let hash = format!("{:x}", hasher.finalize());
+        let blob_path = self.get_blob_path(hash.as_str());
+        if blob_path.exists() {
+            let _ = fs::remove_file(&tmp_path);
+            // Refreshes the mtime so `prune` doesn't delete the blob before the
+            // manifest referencing it is written
+            let _ = File::open(&blob_path).and_then(|f| f.set_modified(SystemTime::now()));
+            return Ok(hash);
+        }
+
+        // Blobs are shared, so nothing should ever modify them in place
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o444))
+            .map_err(|e| Self::copy_error(&tmp_path, e))?;
+        if let Some(parent) = blob_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Self::copy_error(parent, e))?;
+        }
+        fs::rename(&tmp_path, &blob_path).map_err(|e| Self::copy_error(&blob_path, e))?;
+
+        Ok(hash)
+    }
+
+    fn hash_file(path: &Path) -> Result<String, ArtifactError> {
+        let mut file = File::open(path)
+            .map_err(|_| ArtifactError::ArtifactNotFoundError(path.display().to_string()))?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher).map_err(|e| Self::copy_error(path, e))?;
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    // Checks that a stored file's contents still match its hash
+    fn verify_file(path: &Path, hash: &str) -> Result<(), ArtifactError> {
+        if Self::hash_file(path)? != hash {
+            return Err(ArtifactError::ArtifactIntegrityError(
+                path.display().to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Clones the blob where the filesystem supports it, so the data is only
+    // written when either copy changes. Hardlinks aren't used since a job writing
+    // to a loaded file would then modify the blob for everyone else
+    #[cfg(target_os = "linux")]
+    fn reflink(src: &Path, dest: &Path) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let src_file = File::open(src)?;
+        let dest_file = File::create(dest)?;
+        // SAFETY: both file descriptors are valid for the duration of the call
+        let ret =
+            unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) };
+        if ret == -1 {
+            let err = std::io::Error::last_os_error();
+            drop(dest_file);
+            let _ = fs::remove_file(dest);
+            return Err(err);
         }
 
         Ok(())
     }
 
-    pub fn save_artifacts(&self, job_name: &str, paths: Vec<&str>) -> Result<(), ArtifactError> {
-        if paths.is_empty() {
+    #[cfg(not(target_os = "linux"))]
+    fn reflink(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn copy_file(src: &Path, dest: &Path) -> Result<(), ArtifactError> {
+        if Self::reflink(src, dest).is_err() {
+            fs::copy(src, dest).map_err(|e| Self::copy_error(src, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn restore_blob(&self, hash: &str, dest: &Path) -> Result<(), ArtifactError> {
+        Self::copy_file(self.get_blob_path(hash).as_path(), dest)
+    }
+
+    // Adds `rel_path` under `src_root` and everything in it to `rel_paths`, with
+    // directories coming before their contents. Paths matching `exclude` are
+    // skipped
+    fn collect_path(
+        src_root: &Path,
+        rel_path: &Path,
+        exclude: &[Pattern],
+        rel_paths: &mut Vec<PathBuf>,
+    ) -> Result<(), ArtifactError> {
+        if Self::is_excluded(rel_path, exclude) {
             return Ok(());
         }
 
-        let job_artifact_dir = self.get_artifact_dir_for_job(job_name);
-        let _ = fs::create_dir_all(job_artifact_dir.clone());
+        let src = src_root.join(rel_path);
+        let metadata = fs::symlink_metadata(&src).map_err(|e| Self::copy_error(&src, e))?;
+        rel_paths.push(rel_path.to_path_buf());
+
+        if metadata.is_symlink() {
+            let target = fs::read_link(&src).map_err(|e| Self::copy_error(&src, e))?;
+            Self::check_symlink(rel_path, target.as_path())?;
+        } else if metadata.is_dir() {
+            let mut children = vec![];
+            for entry in fs::read_dir(&src).map_err(|e| Self::copy_error(&src, e))? {
+                let entry = entry.map_err(|e| Self::copy_error(&src, e))?;
+                children.push(rel_path.join(entry.file_name()));
+            }
+            children.sort();
+            for child in children {
+                Self::collect_path(src_root, child.as_path(), exclude, rel_paths)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Resolves paths and globs to every path in the workspace that should be
+    // saved, relative to the workspace
+    pub fn collect_paths(
+        workspace: &Path,
+        paths: &[String],
+        exclude: &[String],
+    ) -> Result<Vec<PathBuf>, ArtifactError> {
+        let exclude = exclude
+            .iter()
+            .map(|p| Pattern::new(p.trim_end_matches('/')))
+            .collect::<Result<Vec<Pattern>, _>>()
+            .map_err(|e| ArtifactError::ArtifactNotFoundError(e.to_string()))?;
+
+        let canonical_workspace = workspace
+            .canonicalize()
+            .map_err(|e| Self::copy_error(workspace, e))?;
+        let mut rel_paths = vec![];
+        for path in paths.iter() {
+            // Only the pattern is a glob, the workspace may contain `*` or `[` too
+            let rel_path = Path::new(path.trim_end_matches('/'));
+            if rel_path.is_absolute() || rel_path.components().any(|c| c == Component::ParentDir) {
+                return Err(ArtifactError::ArtifactCopyError(format!(
+                    "{}: artifact paths should be within the workspace",
+                    path
+                )));
+            }
+            let pattern =
+                Path::new(&Pattern::escape(workspace.to_string_lossy().as_ref())).join(rel_path);
+            let mut matches: Vec<PathBuf> = glob(pattern.to_string_lossy().as_ref())
+                .map_err(|e| ArtifactError::ArtifactNotFoundError(e.to_string()))?
+                .flatten()
+                .collect();
+            matches.sort();
 
-        for path in paths {
-            if !fs::exists(path).is_ok() {
+            if matches.is_empty() {
                 return Err(ArtifactError::ArtifactNotFoundError(path.to_string()));
             }
-            Self::copy_path(path, job_artifact_dir.as_str())?;
+
+            for matched in matches {
+                let rel_path = matched
+                    .strip_prefix(workspace)
+                    .map_err(|e| ArtifactError::ArtifactNotFoundError(e.to_string()))?;
+                // Globs follow symlinked directories, so `link/*` could match
+                // files anywhere on the host. The match itself may be a symlink,
+                // which is checked like every other symlink
+                let parent = matched.parent().unwrap_or(workspace);
+                let canonical_parent = parent
+                    .canonicalize()
+                    .map_err(|e| Self::copy_error(parent, e))?;
+                if !canonical_parent.starts_with(&canonical_workspace) {
+                    return Err(ArtifactError::ArtifactCopyError(format!(
+                        "{}: path is behind a symlink pointing outside of the workspace",
+                        rel_path.display()
+                    )));
+                }
+                Self::collect_path(workspace, rel_path, &exclude, &mut rel_paths)?;
+            }
+        }
+
+        // Overlapping paths like `dist` and `dist/*.whl` add the same paths twice
+        let mut seen = HashSet::new();
+        rel_paths.retain(|path| seen.insert(path.clone()));
+
+        Ok(rel_paths)
+    }
+
+    // Stores the contents of a file in the blob store and describes it along with
+    // directories and symlinks in a manifest entry
+    fn store_entry(&self, rel_path: &Path) -> Result<ManifestEntry, ArtifactError> {
+        let src = Path::new(self.workspace.as_str()).join(rel_path);
+        let metadata = fs::symlink_metadata(&src).map_err(|e| Self::copy_error(&src, e))?;
+        let path = rel_path.to_path_buf();
+        let mode = metadata.permissions().mode() & 0o7777;
+        let mtime = metadata.modified().ok();
+
+        let entry = if metadata.is_symlink() {
+            let target = fs::read_link(&src).map_err(|e| Self::copy_error(&src, e))?;
+            ManifestEntry::Symlink { path, target }
+        } else if metadata.is_dir() {
+            ManifestEntry::Dir { path, mode, mtime }
+        } else {
+            ManifestEntry::File {
+                path,
+                hash: self.store_blob(src.as_path())?,
+                size: metadata.len(),
+                mode,
+                mtime,
+            }
+        };
+
+        Ok(entry)
+    }
+
+    fn append_to_archive<W: Write>(
+        workspace: &Path,
+        writer: W,
+        rel_paths: &[PathBuf],
+    ) -> std::io::Result<W> {
+        let mut builder = tar::Builder::new(writer);
+        builder.follow_symlinks(false);
+        for rel_path in rel_paths {
+            builder.append_path_with_name(workspace.join(rel_path), rel_path)?;
+        }
+
+        builder.into_inner()
+    }
+
+    // Archives `rel_paths` of `workspace`
+    pub fn write_archive(
+        workspace: &Path,
+        archive_path: &Path,
+        format: ArtifactFormat,
+        rel_paths: &[PathBuf],
+    ) -> std::io::Result<()> {
+        let file = BufWriter::new(File::create(archive_path)?);
+        let file = match format {
+            ArtifactFormat::Files | ArtifactFormat::Tar => {
+                Self::append_to_archive(workspace, file, rel_paths)?
+            }
+            ArtifactFormat::TarGz => Self::append_to_archive(
+                workspace,
+                GzEncoder::new(file, Compression::default()),
+                rel_paths,
+            )?
+            .finish()?,
+            ArtifactFormat::TarZst => {
+                Self::append_to_archive(workspace, zstd::Encoder::new(file, 0)?, rel_paths)?
+                    .finish()?
+            }
+        };
+        file.into_inner()?.sync_all()
+    }
+
+    // Saves everything matching the artifact paths in the workspace, keeping the
+    // directory structure relative to the workspace. Paths can be globs like
+    // `dist/**/*.whl` and every one of them has to match something. Returns the
+    // number of bytes stored
+    pub fn save_artifacts(
+        &self,
+        job_name: &str,
+        artifacts: &ArtifactsConfig,
+    ) -> Result<u64, ArtifactError> {
+        if artifacts.paths.is_empty() {
+            return Ok(0);
+        }
+
+        let workspace = Path::new(self.workspace.as_str());
+        let rel_paths = Self::collect_paths(workspace, &artifacts.paths, &artifacts.exclude)?;
+        let mut manifest = Manifest {
+            job: job_name.to_string(),
+            format: artifacts.format,
+            expires_at: artifacts
+                .expire_in
+                .map(|expire_in| SystemTime::now() + expire_in),
+            ..Default::default()
+        };
+
+        let size = if let Some(archive_name) = artifacts.format.archive_name() {
+            let job_artifact_dir = self.get_artifact_dir_for_job(job_name);
+            fs::create_dir_all(&job_artifact_dir)
+                .map_err(|e| Self::copy_error(&job_artifact_dir, e))?;
+            let archive_path = job_artifact_dir.join(archive_name);
+            Self::write_archive(
+                workspace,
+                archive_path.as_path(),
+                artifacts.format,
+                &rel_paths,
+            )
+            .map_err(|e| Self::copy_error(&archive_path, e))?;
+
+            manifest.archive_hash = Some(Self::hash_file(archive_path.as_path())?);
+            fs::metadata(&archive_path)
+                .map_err(|e| Self::copy_error(&archive_path, e))?
+                .len()
+        } else {
+            for rel_path in rel_paths {
+                manifest.entries.push(self.store_entry(rel_path.as_path())?);
+            }
+            manifest
+                .entries
+                .iter()
+                .map(|entry| match entry {
+                    ManifestEntry::File { size, .. } => *size,
+                    _ => 0,
+                })
+                .sum()
+        };
+
+        self.write_manifest(job_name, &manifest)?;
+        Ok(size)
+    }
+
+    fn write_manifest(&self, job_name: &str, manifest: &Manifest) -> Result<(), ArtifactError> {
+        let job_artifact_dir = self.get_artifact_dir_for_job(job_name);
+        fs::create_dir_all(&job_artifact_dir)
+            .map_err(|e| Self::copy_error(&job_artifact_dir, e))?;
+
+        let manifest_path = job_artifact_dir.join(MANIFEST_FILE);
+        let tmp_path = job_artifact_dir.join(format!(".{}.tmp", MANIFEST_FILE));
+        let contents = serde_json::to_vec_pretty(manifest)
+            .map_err(|e| ArtifactError::ArtifactCopyError(e.to_string()))?;
+        fs::write(&tmp_path, contents).map_err(|e| Self::copy_error(&tmp_path, e))?;
+        fs::rename(&tmp_path, &manifest_path).map_err(|e| Self::copy_error(&manifest_path, e))
+    }
+
+    // Jobs without artifacts have no manifest
+    fn read_manifest(&self, job_name: &str) -> Result<Option<Manifest>, ArtifactError> {
+        Self::read_manifest_at(
+            self.get_artifact_dir_for_job(job_name)
+                .join(MANIFEST_FILE)
+                .as_path(),
+        )
+    }
+
+    fn read_manifest_at(manifest_path: &Path) -> Result<Option<Manifest>, ArtifactError> {
+        let contents = match fs::read(manifest_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Self::copy_error(manifest_path, e)),
+        };
+
+        let integrity_error = |e: String| {
+            ArtifactError::ArtifactIntegrityError(format!("{}: {}", manifest_path.display(), e))
+        };
+        let manifest: Manifest =
+            serde_json::from_slice(&contents).map_err(|e| integrity_error(e.to_string()))?;
+        manifest.validate().map_err(integrity_error)?;
+        Ok(Some(manifest))
+    }
+
+    // Keeps the variables of a dotenv report next to the artifacts of the job,
+    // so cached results pass them on too
+    pub fn save_dotenv(&self, job_name: &str, variables: &[Variable]) -> Result<(), ArtifactError> {
+        let job_artifact_dir = self.get_artifact_dir_for_job(job_name);
+        fs::create_dir_all(&job_artifact_dir)
+            .map_err(|e| Self::copy_error(&job_artifact_dir, e))?;
+
+        // Values are always quoted, so quotes they contain survive reading
+        // them back
+        let contents: String = variables
+            .iter()
+            .map(|v| format!("{}=\"{}\"\n", v.key, v.value))
+            .collect();
+        let dotenv_path = job_artifact_dir.join(DOTENV_FILE);
+        fs::write(&dotenv_path, contents).map_err(|e| Self::copy_error(&dotenv_path, e))
+    }
+
+    // Jobs without a dotenv report have no variables to pass on
+    pub fn load_dotenv(&self, job_name: &str) -> Result<Vec<Variable>, ArtifactError> {
+        let dotenv_path = self.get_artifact_dir_for_job(job_name).join(DOTENV_FILE);
+        let contents = match fs::read_to_string(&dotenv_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(Self::copy_error(&dotenv_path, e)),
+        };
+
+        parse_dotenv(contents.as_str()).map_err(|e| {
+            ArtifactError::ArtifactIntegrityError(format!("{}: {}", dotenv_path.display(), e))
+        })
+    }
+
+    fn unpack_archive<R: Read>(reader: R, dest_root: &Path) -> Result<(), ArtifactError> {
+        let archive_error = |e: std::io::Error| ArtifactError::ArtifactCopyError(e.to_string());
+        let mut archive = tar::Archive::new(reader);
+        archive.set_preserve_permissions(true);
+
+        let mut dirs = vec![];
+        for entry in archive.entries().map_err(archive_error)? {
+            let mut entry = entry.map_err(archive_error)?;
+            let path = entry.path().map_err(archive_error)?.to_path_buf();
+            // `unpack_in` would skip these, but their metadata would still be
+            // applied outside of `dest_root` below
+            if !path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(ArtifactError::ArtifactIntegrityError(format!(
+                    "archive entry {} is outside of the destination",
+                    path.display()
+                )));
+            }
+            let header = entry.header();
+
+            if header.entry_type().is_symlink() {
+                let target = entry
+                    .link_name()
+                    .map_err(archive_error)?
+                    .unwrap_or_default()
+                    .to_path_buf();
+                Self::check_symlink(path.as_path(), target.as_path())?;
+            } else if header.entry_type().is_dir() {
+                let mode = header.mode().map_err(archive_error)?;
+                let mtime = header
+                    .mtime()
+                    .ok()
+                    .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+                dirs.push((path.clone(), mode, mtime));
+            }
+
+            entry.unpack_in(dest_root).map_err(archive_error)?;
+        }
+
+        // Done last since unpacking the contents changes the directories' mtimes
+        // and their permissions might not allow writing to them
+        for (path, mode, mtime) in dirs.into_iter().rev() {
+            Self::set_metadata(dest_root.join(path).as_path(), mode, mtime)?;
         }
 
         Ok(())
     }
 
-    // Loads all artifacts created by 'job_name'
+    // Archives are decompressed and unpacked as they are read, without
+    // decompressing them to disk first
+    pub fn extract_archive(
+        archive_path: &Path,
+        format: ArtifactFormat,
+        dest_root: &Path,
+    ) -> Result<(), ArtifactError> {
+        let file = BufReader::new(
+            File::open(archive_path).map_err(|e| Self::copy_error(archive_path, e))?,
+        );
+        match format {
+            ArtifactFormat::Files | ArtifactFormat::Tar => Self::unpack_archive(file, dest_root),
+            ArtifactFormat::TarGz => Self::unpack_archive(GzDecoder::new(file), dest_root),
+            ArtifactFormat::TarZst => Self::unpack_archive(
+                zstd::Decoder::with_buffer(file).map_err(|e| Self::copy_error(archive_path, e))?,
+                dest_root,
+            ),
+        }
+    }
+
+    // Loads all artifacts created by 'job_name'. Every blob is verified against
+    // its hash before it is restored
     pub fn load_artifacts(
         &self,
         from_job_name: &str,
         to_job_name: &str,
     ) -> Result<(), ArtifactError> {
-        let job_artifact_dir = self.get_artifact_dir_for_job(from_job_name);
-        if !fs::exists(job_artifact_dir.as_str()).is_ok() {
-            return Err(ArtifactError::ArtifactNotFoundError(
-                job_artifact_dir.clone(),
-            ));
+        let Some(manifest) = self.read_manifest(from_job_name)? else {
+            return Ok(());
+        };
+
+        // Jobs find the artifacts they load under their own name, so unlike the
+        // run directory this keeps the name readable
+        let dest_root = Path::new(self.workspace.as_str()).join(to_job_name.replace('/', "_"));
+        self.restore_artifacts(from_job_name, &manifest, dest_root.as_path())
+    }
+
+    // Restores the artifacts of a job, which might be from an earlier run, into
+    // `dest_root`. Unlike `load_artifacts` it is an error if the job has none
+    pub fn get_artifacts(&self, job_name: &str, dest_root: &Path) -> Result<(), ArtifactError> {
+        let Some(manifest) = self.read_manifest(job_name)? else {
+            return Err(ArtifactError::ArtifactNotFoundError(format!(
+                "{} in run {}",
+                job_name, self.run_id
+            )));
+        };
+        self.restore_artifacts(job_name, &manifest, dest_root)
+    }
+
+    fn restore_artifacts(
+        &self,
+        from_job_name: &str,
+        manifest: &Manifest,
+        dest_root: &Path,
+    ) -> Result<(), ArtifactError> {
+        fs::create_dir_all(dest_root).map_err(|e| Self::copy_error(dest_root, e))?;
+
+        if let Some(archive_name) = manifest.format.archive_name() {
+            let archive_path = self
+                .get_artifact_dir_for_job(from_job_name)
+                .join(archive_name);
+            if let Some(ref archive_hash) = manifest.archive_hash {
+                Self::verify_file(archive_path.as_path(), archive_hash.as_str())?;
+            }
+            return Self::extract_archive(archive_path.as_path(), manifest.format, dest_root);
         }
 
-        // TODO: Improve copying of files
-        for entry in glob(format!("{}/*", job_artifact_dir).as_str())
-            .map_err(|e| ArtifactError::ArtifactNotFoundError(e.to_string()))?
-        {
-            if let Ok(file_path) = entry {
-                let dest = format!("{}/{}", self.workspace, to_job_name);
-                let _ = fs::create_dir_all(dest.clone());
-                Self::copy_path(
-                    file_path
-                        .to_str()
-                        .ok_or(ArtifactError::ArtifactNotFoundError(
-                            "could not get file name".to_string(),
-                        ))?,
-                    dest.as_str(),
+        let mut verified = HashSet::new();
+        for entry in manifest.entries.iter() {
+            match entry {
+                ManifestEntry::Dir { path, .. } => {
+                    let dest = dest_root.join(path);
+                    fs::create_dir_all(&dest).map_err(|e| Self::copy_error(&dest, e))?;
+                }
+                ManifestEntry::File {
+                    path,
+                    hash,
+                    mode,
+                    mtime,
+                    ..
+                } => {
+                    if verified.insert(hash) {
+                        Self::verify_file(self.get_blob_path(hash).as_path(), hash.as_str())?;
+                    }
+
+                    let dest = dest_root.join(path);
+                    if let Some(parent) = dest.parent() {
+                        fs::create_dir_all(parent).map_err(|e| Self::copy_error(parent, e))?;
+                    }
+                    Self::remove_existing(dest.as_path())?;
+                    self.restore_blob(hash.as_str(), dest.as_path())?;
+                    Self::set_metadata(dest.as_path(), *mode, *mtime)?;
+                }
+                ManifestEntry::Symlink { path, target } => {
+                    Self::check_symlink(path.as_path(), target.as_path())?;
+                    let dest = dest_root.join(path);
+                    if let Some(parent) = dest.parent() {
+                        fs::create_dir_all(parent).map_err(|e| Self::copy_error(parent, e))?;
+                    }
+                    Self::remove_existing(dest.as_path())?;
+                    symlink(target, &dest).map_err(|e| Self::copy_error(&dest, e))?;
+                }
+            }
+        }
+
+        // Done last since restoring the contents changes the directories' mtimes
+        // and their permissions might not allow writing to them
+        for entry in manifest.entries.iter().rev() {
+            if let ManifestEntry::Dir { path, mode, mtime } = entry {
+                Self::set_metadata(dest_root.join(path).as_path(), *mode, *mtime)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Hashes what a job saved, leaving out mtimes so rebuilding identical files
+    // gives the same hash. Returns None if the job saved nothing
+    pub fn artifacts_hash(&self, job_name: &str) -> Result<Option<String>, ArtifactError> {
+        let Some(manifest) = self.read_manifest(job_name)? else {
+            return Ok(None);
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(manifest.format.name());
+        hasher.update(manifest.archive_hash.unwrap_or_default());
+        for entry in manifest.entries {
+            let entry = match entry {
+                ManifestEntry::File {
+                    path, hash, mode, ..
+                } => format!("file {} {} {:o}", path.display(), hash, mode),
+                ManifestEntry::Dir { path, mode, .. } => {
+                    format!("dir {} {:o}", path.display(), mode)
+                }
+                ManifestEntry::Symlink { path, target } => {
+                    format!("symlink {} {}", path.display(), target.display())
+                }
+            };
+            hasher.update(entry);
+            hasher.update([0]);
+        }
+
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    fn get_result_dir(&self, key: &str) -> PathBuf {
+        Path::new(self.root_dir.as_str())
+            .join(RESULTS_DIR)
+            .join(key)
+    }
+
+    // Keeps the log, report files and artifacts of a successful job under `key`.
+    // Results are stored next to the runs so `prune` keeps the blobs they use and
+    // expires them along with their artifacts
+    pub fn save_result(
+        &self,
+        job_name: &str,
+        key: &str,
+        log: &str,
+        reports: &[PathBuf],
+    ) -> Result<(), ArtifactError> {
+        let result_dir = self.get_result_dir(key);
+        if result_dir.exists() {
+            return Ok(());
+        }
+
+        let tmp_dir = result_dir.with_file_name(format!(
+            ".{}.tmp-{}-{:?}",
+            key,
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        fs::create_dir_all(&tmp_dir).map_err(|e| Self::copy_error(&tmp_dir, e))?;
+        let job_artifact_dir = self.get_artifact_dir_for_job(job_name);
+        if let Ok(entries) = fs::read_dir(&job_artifact_dir) {
+            for entry in entries.flatten() {
+                Self::copy_file(
+                    entry.path().as_path(),
+                    tmp_dir.join(entry.file_name()).as_path(),
                 )?;
             }
         }
+        let log_path = tmp_dir.join(RESULT_LOG);
+        fs::write(&log_path, log).map_err(|e| Self::copy_error(&log_path, e))?;
+        let reports_dir = tmp_dir.join(RESULT_REPORTS);
+        fs::create_dir_all(&reports_dir).map_err(|e| Self::copy_error(&reports_dir, e))?;
+        // Reports from different directories may share a file name
+        for (i, report) in reports.iter().enumerate() {
+            let file_name = report.file_name().unwrap_or_default().to_string_lossy();
+            Self::copy_file(
+                report.as_path(),
+                reports_dir.join(format!("{}-{}", i, file_name)).as_path(),
+            )?;
+        }
 
+        // Another job with the same key might have finished first, in which case
+        // its result is kept
+        if fs::rename(&tmp_dir, &result_dir).is_err() {
+            let _ = fs::remove_dir_all(&tmp_dir);
+        }
         Ok(())
     }
 
-    pub fn cleanup(&self) -> Result<(), ArtifactError> {
-        fs::remove_dir_all(self.root_dir.as_str())
-            .map_err(|e| ArtifactError::ArtifactCleanupError(e.to_string()))?;
+    // Makes the artifacts of a result stored under `key` the artifacts of
+    // `job_name` in this run. Returns the log and report files of the job that
+    // produced it
+    pub fn restore_result(
+        &self,
+        job_name: &str,
+        key: &str,
+    ) -> Result<Option<CachedResult>, ArtifactError> {
+        let result_dir = self.get_result_dir(key);
+        let log_path = result_dir.join(RESULT_LOG);
+        let log = match fs::read_to_string(&log_path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Self::copy_error(&log_path, e)),
+        };
+        // The log's mtime is when the result was last used, which `prune` expires
+        // results by
+        let _ = File::options()
+            .write(true)
+            .open(&log_path)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+
+        let job_artifact_dir = self.get_artifact_dir_for_job(job_name);
+        fs::create_dir_all(&job_artifact_dir)
+            .map_err(|e| Self::copy_error(&job_artifact_dir, e))?;
+        for entry in fs::read_dir(&result_dir).map_err(|e| Self::copy_error(&result_dir, e))? {
+            let entry = entry.map_err(|e| Self::copy_error(&result_dir, e))?;
+            if entry.file_name() != RESULT_LOG && entry.file_name() != RESULT_REPORTS {
+                let dest = job_artifact_dir.join(entry.file_name());
+                Self::remove_existing(dest.as_path())?;
+                Self::copy_file(entry.path().as_path(), dest.as_path())?;
+            }
+        }
+
+        let reports_dir = result_dir.join(RESULT_REPORTS);
+        let mut reports = vec![];
+        if let Ok(entries) = fs::read_dir(&reports_dir) {
+            for entry in entries {
+                reports.push(entry.map_err(|e| Self::copy_error(&reports_dir, e))?.path());
+            }
+        }
+        reports.sort();
+
+        Ok(Some(CachedResult { log, reports }))
+    }
+
+    fn find_manifests(dir: &Path, manifests: &mut Vec<PathBuf>) -> Result<(), ArtifactError> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Self::copy_error(dir, e)),
+        };
+        for entry in entries {
+            let entry = entry.map_err(|e| Self::copy_error(dir, e))?;
+            let path = entry.path();
+            let file_type = entry.file_type().map_err(|e| Self::copy_error(&path, e))?;
+            if file_type.is_dir() {
+                Self::find_manifests(path.as_path(), manifests)?;
+            } else if entry.file_name() == MANIFEST_FILE {
+                manifests.push(path);
+            }
+        }
 
         Ok(())
     }
+
+    fn dir_size(dir: &Path) -> u64 {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return 0;
+        };
+        entries
+            .flatten()
+            .map(|entry| match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => Self::dir_size(entry.path().as_path()),
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            })
+            .sum()
+    }
+
+    // Deletes all artifacts whose `expire_in` has passed, followed by the blobs
+    // no remaining artifact references
+    pub fn prune(&self) -> Result<PruneSummary, ArtifactError> {
+        let root_dir = Path::new(self.root_dir.as_str());
+        let blobs_dir = root_dir.join(BLOBS_DIR);
+        let now = SystemTime::now();
+        let mut summary = PruneSummary::default();
+
+        // Before the manifests are read, so the blobs of expired results go too
+        let results_dir = root_dir.join(RESULTS_DIR);
+        if let Ok(results) = fs::read_dir(&results_dir) {
+            for result in results.flatten() {
+                let result_dir = result.path();
+                let is_expired = fs::metadata(result_dir.join(RESULT_LOG))
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|mtime| now.duration_since(mtime).ok())
+                    .is_some_and(|age| age >= RESULT_EXPIRY);
+                if !is_expired {
+                    continue;
+                }
+
+                summary.freed += Self::dir_size(&result_dir);
+                fs::remove_dir_all(&result_dir)
+                    .map_err(|e| ArtifactError::ArtifactCleanupError(e.to_string()))?;
+                summary.results += 1;
+            }
+        }
+
+        let mut manifest_paths = vec![];
+        let entries = match fs::read_dir(root_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(summary),
+            Err(e) => return Err(Self::copy_error(root_dir, e)),
+        };
+        for entry in entries {
+            let path = entry.map_err(|e| Self::copy_error(root_dir, e))?.path();
+            if path != blobs_dir && path.is_dir() {
+                Self::find_manifests(path.as_path(), &mut manifest_paths)?;
+            }
+        }
+
+        let mut referenced = HashSet::new();
+        for manifest_path in manifest_paths {
+            let contents =
+                fs::read(&manifest_path).map_err(|e| Self::copy_error(&manifest_path, e))?;
+            let manifest: Manifest = serde_json::from_slice(&contents).map_err(|e| {
+                ArtifactError::ArtifactIntegrityError(format!("{}: {}", manifest_path.display(), e))
+            })?;
+
+            if manifest
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+            {
+                let Some(job_artifact_dir) = manifest_path.parent() else {
+                    continue;
+                };
+                summary.freed += Self::dir_size(job_artifact_dir);
+                fs::remove_dir_all(job_artifact_dir)
+                    .map_err(|e| ArtifactError::ArtifactCleanupError(e.to_string()))?;
+                // Only succeeds once all jobs of the run are gone
+                if let Some(run_dir) = job_artifact_dir.parent() {
+                    let _ = fs::remove_dir(run_dir);
+                }
+                summary.artifacts += 1;
+                continue;
+            }
+
+            for entry in manifest.entries {
+                if let ManifestEntry::File { hash, .. } = entry {
+                    referenced.insert(hash);
+                }
+            }
+        }
+
+        let Ok(prefix_dirs) = fs::read_dir(&blobs_dir) else {
+            return Ok(summary);
+        };
+        for prefix_dir in prefix_dirs.flatten() {
+            let Ok(blobs) = fs::read_dir(prefix_dir.path()) else {
+                continue;
+            };
+            for blob in blobs.flatten() {
+                let Some(hash) = blob.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                let Ok(metadata) = blob.metadata() else {
+                    continue;
+                };
+                let is_recent = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|mtime| now.duration_since(mtime).ok())
+                    .is_none_or(|age| age < BLOB_GRACE_PERIOD);
+                if referenced.contains(&hash) || is_recent {
+                    continue;
+                }
+
+                fs::remove_file(blob.path())
+                    .map_err(|e| ArtifactError::ArtifactCleanupError(e.to_string()))?;
+                summary.blobs += 1;
+                summary.freed += metadata.len();
+            }
+            // Only succeeds once the directory is empty
+            let _ = fs::remove_dir(prefix_dir.path());
+        }
+
+        Ok(summary)
+    }
+
+    // Runs are listed oldest first since run ids start with their start time
+    pub fn list_runs(&self) -> Result<Vec<String>, ArtifactError> {
+        let runs_dir = Path::new(self.root_dir.as_str()).join(RUNS_DIR);
+        let entries = match fs::read_dir(&runs_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(Self::copy_error(&runs_dir, e)),
+        };
+
+        let mut runs: Vec<String> = entries
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+            .collect();
+        runs.sort();
+        Ok(runs)
+    }
+
+    // Returns the jobs of this run that saved artifacts along with their size
+    pub fn list_jobs(&self) -> Result<Vec<(String, u64)>, ArtifactError> {
+        let run_dir = self.get_run_dir();
+        let entries = match fs::read_dir(&run_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ArtifactError::ArtifactNotFoundError(format!(
+                    "run {}",
+                    self.run_id
+                )));
+            }
+            Err(e) => return Err(Self::copy_error(&run_dir, e)),
+        };
+
+        let mut jobs = vec![];
+        for entry in entries.flatten() {
+            if let Some(manifest) =
+                Self::read_manifest_at(entry.path().join(MANIFEST_FILE).as_path())?
+            {
+                let size = self.artifacts_size(manifest.job.as_str(), &manifest);
+                jobs.push((manifest.job, size));
+            }
+        }
+        jobs.sort();
+        Ok(jobs)
+    }
+
+    fn artifacts_size(&self, job_name: &str, manifest: &Manifest) -> u64 {
+        if let Some(archive_name) = manifest.format.archive_name() {
+            let archive_path = self.get_artifact_dir_for_job(job_name).join(archive_name);
+            return fs::metadata(archive_path).map(|m| m.len()).unwrap_or(0);
+        }
+        manifest
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                ManifestEntry::File { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    // Describes every saved path of a job, one per line
+    pub fn list_artifacts(&self, job_name: &str) -> Result<Vec<String>, ArtifactError> {
+        let Some(manifest) = self.read_manifest(job_name)? else {
+            return Err(ArtifactError::ArtifactNotFoundError(format!(
+                "{} in run {}",
+                job_name, self.run_id
+            )));
+        };
+
+        if let Some(archive_name) = manifest.format.archive_name() {
+            let size = self.artifacts_size(job_name, &manifest);
+            return Ok(vec![format!("{}  {}", archive_name, format_size(size))]);
+        }
+        Ok(manifest
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                ManifestEntry::File { path, size, .. } => {
+                    format!("{}  {}", path.display(), format_size(*size))
+                }
+                ManifestEntry::Dir { path, .. } => format!("{}/", path.display()),
+                ManifestEntry::Symlink { path, target } => {
+                    format!("{} -> {}", path.display(), target.display())
+                }
+            })
+            .collect())
+    }
+
+    // Removes the artifacts of this run. The blobs they used can be shared with
+    // other runs, so unreferenced ones are left for the `prune` command. Other
+    // runs are never looked at, they might still be running
+    pub fn cleanup(&self) -> Result<(), ArtifactError> {
+        match fs::remove_dir_all(self.get_run_dir()) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(ArtifactError::ArtifactCleanupError(e.to_string())),
+        }
+    }
+}
+
+// Formats a number of bytes for humans, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn artifacts(paths: &[&str], exclude: &[&str]) -> ArtifactsConfig {
+        ArtifactsConfig {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    // Managers of the same test share the workspace and artifact root in
+    // `test_dir` and only differ in their run
+    fn artifact_manager(test_dir: &Path, run_id: &str) -> ArtifactManager {
+        ArtifactManager::new_with_params(
+            test_dir.join("workspace").display().to_string(),
+            test_dir.join("artifacts").display().to_string(),
+            run_id.to_string(),
+        )
+    }
+
+    fn create_file(path: &Path) {
+        fs::create_dir_all(path.parent().expect("should have a parent"))
+            .expect("should create dir");
+        fs::write(path, path.display().to_string()).expect("should write file");
+    }
+
+    #[test]
+    fn test_save_and_load_artifacts() {
+        // The `[` makes sure the workspace isn't read as part of a glob
+        let temp_dir = tempfile::Builder::new()
+            .prefix("pipeline-artifacts-[")
+            .tempdir()
+            .expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        for file in [
+            "dist/pkg/app-1.0.whl",
+            "dist/pkg/app-1.0.tar.gz",
+            "dist/cache/app-0.9.whl",
+            "report.xml",
+        ] {
+            create_file(workspace.join(file).as_path());
+        }
+
+        let artifact_manager = artifact_manager(test_dir, "run");
+        artifact_manager
+            .save_artifacts(
+                "build 1/2",
+                &artifacts(&["dist/**/*.whl", "report.xml"], &["dist/cache"]),
+            )
+            .expect("saving artifacts should succeed");
+
+        artifact_manager
+            .load_artifacts("build 1/2", "deploy")
+            .expect("loading artifacts should succeed");
+        let loaded_dir = workspace.join("deploy");
+        assert!(loaded_dir.join("dist/pkg/app-1.0.whl").is_file());
+        assert!(loaded_dir.join("report.xml").is_file());
+        assert!(!loaded_dir.join("dist/pkg/app-1.0.tar.gz").exists());
+        assert!(!loaded_dir.join("dist/cache").exists());
+
+        assert_eq!(
+            artifact_manager.save_artifacts("build 1/2", &artifacts(&["missing/*.whl"], &[])),
+            Err(ArtifactError::ArtifactNotFoundError(
+                "missing/*.whl".to_string()
+            ))
+        );
+        for outside in ["../*", "/etc/hostname"] {
+            assert_eq!(
+                artifact_manager.save_artifacts("build 1/2", &artifacts(&[outside], &[])),
+                Err(ArtifactError::ArtifactCopyError(format!(
+                    "{}: artifact paths should be within the workspace",
+                    outside
+                )))
+            );
+        }
+    }
+
+    #[test]
+    fn test_artifacts_preserve_metadata() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        let binary = workspace.join("bin/app");
+        create_file(binary.as_path());
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755))
+            .expect("should set permissions");
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::File::open(&binary)
+            .and_then(|f| f.set_modified(mtime))
+            .expect("should set mtime");
+        symlink("app", workspace.join("bin/app-latest")).expect("should create symlink");
+
+        let artifact_manager = artifact_manager(test_dir, "run");
+        artifact_manager
+            .save_artifacts("build", &artifacts(&["bin"], &[]))
+            .expect("saving artifacts should succeed");
+        artifact_manager
+            .load_artifacts("build", "deploy")
+            .expect("loading artifacts should succeed");
+
+        let loaded_dir = workspace.join("deploy");
+        let metadata = fs::metadata(loaded_dir.join("bin/app")).expect("binary should exist");
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(metadata.modified().expect("should have mtime"), mtime);
+        assert_eq!(
+            fs::read_link(loaded_dir.join("bin/app-latest")).expect("should be a symlink"),
+            PathBuf::from("app")
+        );
+
+        symlink("../../../etc/passwd", workspace.join("bin/passwd"))
+            .expect("should create symlink");
+        assert_eq!(
+            artifact_manager.save_artifacts("build", &artifacts(&["bin"], &[])),
+            Err(ArtifactError::ArtifactCopyError(
+                "bin/passwd: symlink to ../../../etc/passwd points outside of the workspace"
+                    .to_string()
+            ))
+        );
+
+        create_file(test_dir.join("outside/secret.txt").as_path());
+        symlink("../outside", workspace.join("linked")).expect("should create symlink");
+        assert_eq!(
+            artifact_manager.save_artifacts("build", &artifacts(&["linked/*.txt"], &[])),
+            Err(ArtifactError::ArtifactCopyError(
+                "linked/secret.txt: path is behind a symlink pointing outside of the workspace"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_artifacts_are_deduplicated() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        for file in ["a/app.txt", "b/app.txt"] {
+            fs::create_dir_all(workspace.join(file).parent().expect("should have a parent"))
+                .expect("should create dir");
+            fs::write(workspace.join(file), "app-v1.0.0").expect("should write file");
+        }
+
+        let artifact_manager = artifact_manager(test_dir, "run");
+        for (job_name, path) in [("build-a", "a"), ("build-b", "b")] {
+            artifact_manager
+                .save_artifacts(job_name, &artifacts(&[path], &[]))
+                .expect("saving artifacts should succeed");
+        }
+
+        let blobs: Vec<PathBuf> =
+            glob(format!("{}/{}/*/*", artifact_manager.root_dir, BLOBS_DIR).as_str())
+                .expect("pattern should be valid")
+                .flatten()
+                .collect();
+        assert_eq!(blobs.len(), 1);
+
+        // A blob that doesn't match its hash anymore must not be loaded
+        fs::set_permissions(&blobs[0], fs::Permissions::from_mode(0o644))
+            .expect("should set permissions");
+        fs::write(&blobs[0], "tampered").expect("should write blob");
+        assert_eq!(
+            artifact_manager.load_artifacts("build-a", "deploy"),
+            Err(ArtifactError::ArtifactIntegrityError(
+                blobs[0].display().to_string()
+            ))
+        );
+
+        // Neither can a manifest naming blobs or paths outside their directory
+        let manifest_path = artifact_manager
+            .get_artifact_dir_for_job("build-b")
+            .join(MANIFEST_FILE);
+        let manifest = fs::read_to_string(&manifest_path).expect("should read manifest");
+        let hash = blobs[0]
+            .file_name()
+            .expect("should have a name")
+            .to_string_lossy();
+        for (from, to, error) in [
+            (hash.as_ref(), "x", "invalid hash x of b/app.txt"),
+            (
+                "\"b/app.txt\"",
+                "\"../app.txt\"",
+                "invalid entry path ../app.txt",
+            ),
+        ] {
+            fs::write(&manifest_path, manifest.replace(from, to)).expect("should write manifest");
+            assert_eq!(
+                artifact_manager.load_artifacts("build-b", "deploy"),
+                Err(ArtifactError::ArtifactIntegrityError(format!(
+                    "{}: {}",
+                    manifest_path.display(),
+                    error
+                )))
+            );
+        }
+    }
+
+    #[test]
+    fn test_archive_artifacts() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        let binary = workspace.join("bin/app");
+        create_file(binary.as_path());
+        create_file(workspace.join("bin/cache/old").as_path());
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755))
+            .expect("should set permissions");
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::open(&binary)
+            .and_then(|f| f.set_modified(mtime))
+            .expect("should set mtime");
+        symlink("app", workspace.join("bin/app-latest")).expect("should create symlink");
+
+        let artifact_manager = artifact_manager(test_dir, "run");
+        for format in [
+            ArtifactFormat::Tar,
+            ArtifactFormat::TarGz,
+            ArtifactFormat::TarZst,
+        ] {
+            let job_name = format!("build-{}", format.name());
+            let mut config = artifacts(&["bin"], &["bin/cache"]);
+            config.format = format;
+            let size = artifact_manager
+                .save_artifacts(job_name.as_str(), &config)
+                .expect("saving artifacts should succeed");
+            let archive_path = artifact_manager
+                .get_artifact_dir_for_job(&job_name)
+                .join(format.archive_name().expect("should be an archive"));
+            assert_eq!(
+                fs::metadata(archive_path)
+                    .expect("archive should exist")
+                    .len(),
+                size
+            );
+
+            let to_job_name = format!("deploy-{}", format.name());
+            artifact_manager
+                .load_artifacts(job_name.as_str(), to_job_name.as_str())
+                .expect("loading artifacts should succeed");
+
+            let loaded_dir = workspace.join(to_job_name);
+            let metadata = fs::metadata(loaded_dir.join("bin/app")).expect("binary should exist");
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+            assert_eq!(metadata.modified().expect("should have mtime"), mtime);
+            assert_eq!(
+                fs::read_link(loaded_dir.join("bin/app-latest")).expect("should be a symlink"),
+                PathBuf::from("app")
+            );
+            assert!(!loaded_dir.join("bin/cache").exists());
+        }
+
+        // `tar` refuses to write `..` paths, so the name is set by hand
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o777);
+        header.set_size(0);
+        header.as_old_mut().name[..9].copy_from_slice(b"../escape");
+        header.set_cksum();
+        let mut builder = tar::Builder::new(vec![]);
+        builder
+            .append(&header, std::io::empty())
+            .expect("should append entry");
+        let archive = builder.into_inner().expect("should finish archive");
+        assert_eq!(
+            ArtifactManager::unpack_archive(archive.as_slice(), workspace.as_path()),
+            Err(ArtifactError::ArtifactIntegrityError(
+                "archive entry ../escape is outside of the destination".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_prune_artifacts() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        create_file(workspace.join("expired.txt").as_path());
+        create_file(workspace.join("kept.txt").as_path());
+
+        let artifact_manager = artifact_manager(test_dir, "run");
+        let mut expired = artifacts(&["expired.txt"], &[]);
+        expired.expire_in = Some(Duration::ZERO);
+        artifact_manager
+            .save_artifacts("expired", &expired)
+            .expect("saving artifacts should succeed");
+        let mut kept = artifacts(&["kept.txt"], &[]);
+        kept.expire_in = Some(Duration::from_secs(60 * 60));
+        artifact_manager
+            .save_artifacts("kept", &kept)
+            .expect("saving artifacts should succeed");
+
+        let expired_hash = ArtifactManager::hash_file(workspace.join("expired.txt").as_path())
+            .expect("should hash file");
+        let expired_blob = artifact_manager.get_blob_path(expired_hash.as_str());
+        let old = SystemTime::now() - 2 * BLOB_GRACE_PERIOD;
+        File::open(&expired_blob)
+            .and_then(|f| f.set_modified(old))
+            .expect("should set mtime");
+
+        let summary = artifact_manager.prune().expect("pruning should succeed");
+        assert_eq!(summary.artifacts, 1);
+        assert_eq!(summary.blobs, 1);
+        assert!(!expired_blob.exists());
+        assert!(
+            !artifact_manager
+                .get_artifact_dir_for_job("expired")
+                .exists()
+        );
+        assert_eq!(artifact_manager.load_artifacts("expired", "deploy"), Ok(()));
+        assert!(!workspace.join("deploy/expired.txt").exists());
+        artifact_manager
+            .load_artifacts("kept", "deploy")
+            .expect("kept artifacts should load");
+        assert!(workspace.join("deploy/kept.txt").exists());
+    }
+
+    #[test]
+    fn test_artifacts_are_kept_per_run() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        let first_run = artifact_manager(test_dir, "1-1");
+        let second_run = artifact_manager(test_dir, "2-1");
+
+        create_file(workspace.join("dist/app.whl").as_path());
+        first_run
+            .save_artifacts("build 1/2", &artifacts(&["dist"], &[]))
+            .expect("saving artifacts should succeed");
+        fs::write(workspace.join("dist/app.whl"), "second").expect("should write file");
+        second_run
+            .save_artifacts("build 1/2", &artifacts(&["dist"], &[]))
+            .expect("saving artifacts should succeed");
+
+        assert_eq!(
+            first_run.list_runs(),
+            Ok(vec!["1-1".to_string(), "2-1".to_string()])
+        );
+        // Job names that only differ in characters not allowed in directory
+        // names are kept apart
+        create_file(workspace.join("other.txt").as_path());
+        first_run
+            .save_artifacts("build 1_2", &artifacts(&["other.txt"], &[]))
+            .expect("saving artifacts should succeed");
+        let size = workspace.join("dist/app.whl").display().to_string().len() as u64;
+        let other_size = workspace.join("other.txt").display().to_string().len() as u64;
+        assert_eq!(
+            first_run.list_jobs(),
+            Ok(vec![
+                ("build 1/2".to_string(), size),
+                ("build 1_2".to_string(), other_size)
+            ])
+        );
+        assert_eq!(
+            first_run.list_artifacts("build 1/2"),
+            Ok(vec![
+                "dist/".to_string(),
+                format!("dist/app.whl  {} B", size)
+            ])
+        );
+        assert_eq!(
+            first_run.list_artifacts("deploy"),
+            Err(ArtifactError::ArtifactNotFoundError(
+                "deploy in run 1-1".to_string()
+            ))
+        );
+
+        let output = test_dir.join("output");
+        first_run
+            .get_artifacts("build 1/2", output.as_path())
+            .expect("getting artifacts should succeed");
+        assert_eq!(
+            fs::read_to_string(output.join("dist/app.whl")).expect("file should exist"),
+            workspace.join("dist/app.whl").display().to_string()
+        );
+
+        second_run.cleanup().expect("cleanup should succeed");
+        assert_eq!(first_run.list_runs(), Ok(vec!["1-1".to_string()]));
+        assert!(first_run.list_jobs().is_ok());
+    }
+
+    #[test]
+    fn test_save_and_restore_result() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        let first_run = artifact_manager(test_dir, "1-1");
+        let second_run = artifact_manager(test_dir, "2-1");
+
+        create_file(workspace.join("dist/app.whl").as_path());
+        first_run
+            .save_artifacts("build", &artifacts(&["dist"], &[]))
+            .expect("saving artifacts should succeed");
+        let hash = first_run
+            .artifacts_hash("build")
+            .expect("should hash artifacts");
+        assert!(hash.is_some());
+        assert_eq!(first_run.artifacts_hash("lint"), Ok(None));
+
+        // Rebuilding the same file only changes its mtime, which is not hashed
+        File::open(workspace.join("dist/app.whl"))
+            .and_then(|f| f.set_modified(SystemTime::UNIX_EPOCH))
+            .expect("should set mtime");
+        second_run
+            .save_artifacts("build", &artifacts(&["dist"], &[]))
+            .expect("saving artifacts should succeed");
+        assert_eq!(second_run.artifacts_hash("build"), Ok(hash));
+        fs::remove_dir_all(second_run.get_run_dir()).expect("should remove run");
+
+        assert_eq!(second_run.restore_result("build", "key"), Ok(None));
+        let dotenv = vec![Variable {
+            key: "VERSION".to_string(),
+            value: "\"1.2.3\"".to_string(),
+        }];
+        assert_eq!(first_run.load_dotenv("build"), Ok(vec![]));
+        first_run
+            .save_dotenv("build", &dotenv)
+            .expect("saving dotenv should succeed");
+        create_file(workspace.join("reports/junit.xml").as_path());
+        first_run
+            .save_result(
+                "build",
+                "key",
+                "built\n",
+                &[workspace.join("reports/junit.xml")],
+            )
+            .expect("saving result should succeed");
+        // The stored report is read, not the one left in the workspace
+        fs::remove_dir_all(workspace.join("reports")).expect("should remove reports");
+        let result = second_run
+            .restore_result("build", "key")
+            .expect("restoring result should succeed")
+            .expect("result should exist");
+        assert_eq!(result.log, "built\n");
+        assert_eq!(result.reports.len(), 1);
+        assert_eq!(
+            fs::read_to_string(&result.reports[0]).expect("report should exist"),
+            workspace.join("reports/junit.xml").display().to_string()
+        );
+        second_run
+            .load_artifacts("build", "deploy")
+            .expect("restored artifacts should load");
+        assert!(workspace.join("deploy/dist/app.whl").exists());
+        assert_eq!(second_run.load_dotenv("build"), Ok(dotenv));
+
+        // Results keep their blobs alive once the run is gone
+        first_run.cleanup().expect("cleanup should succeed");
+        second_run.cleanup().expect("cleanup should succeed");
+        first_run.prune().expect("pruning should succeed");
+        let third_run = ArtifactManager {
+            run_id: "3-1".to_string(),
+            ..first_run.clone()
+        };
+        assert_eq!(
+            third_run
+                .restore_result("build", "key")
+                .map(|result| result.map(|r| r.log)),
+            Ok(Some("built\n".to_string()))
+        );
+        third_run
+            .load_artifacts("build", "deploy")
+            .expect("restored artifacts should load");
+
+        // Results that weren't used for too long expire along with their blobs
+        third_run.cleanup().expect("cleanup should succeed");
+        let old = SystemTime::now() - RESULT_EXPIRY - BLOB_GRACE_PERIOD;
+        let result_dir = third_run.get_result_dir("key");
+        File::open(result_dir.join(RESULT_LOG))
+            .and_then(|f| f.set_modified(old))
+            .expect("should set mtime");
+        for blob in fs::read_dir(Path::new(third_run.root_dir.as_str()).join(BLOBS_DIR))
+            .expect("blobs should exist")
+            .flatten()
+            .flat_map(|prefix_dir| fs::read_dir(prefix_dir.path()).expect("should read dir"))
+            .flatten()
+        {
+            File::open(blob.path())
+                .and_then(|f| f.set_modified(old))
+                .expect("should set mtime");
+        }
+        let summary = third_run.prune().expect("pruning should succeed");
+        assert_eq!(summary.results, 1);
+        assert_eq!(summary.blobs, 1);
+        assert!(!result_dir.exists());
+        assert_eq!(third_run.restore_result("build", "key"), Ok(None));
+    }
 }
@@ -0,0 +1,283 @@
+use crate::artifact_manager::ArtifactManager;
+use crate::error::PipelineError;
+use crate::error::PipelineError::CacheError;
+use crate::job::{ArtifactFormat, CacheConfig, CacheKey};
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use glob::{Pattern, glob};
+use sha2::{Digest, Sha256};
+
+const CACHE_ARCHIVE: &str = "cache.tar.zst";
+const CACHE_FORMAT: ArtifactFormat = ArtifactFormat::TarZst;
+
+// Feeds the names and contents of all files in `workspace` matching `globs` into
+// `hasher`, in a stable order. Returns whether anything matched
+pub fn hash_files(
+    workspace: &Path,
+    globs: &[String],
+    hasher: &mut Sha256,
+) -> Result<bool, PipelineError> {
+    let mut paths = vec![];
+    for file in globs {
+        let pattern = Path::new(&Pattern::escape(workspace.to_string_lossy().as_ref())).join(file);
+        let matches = glob(pattern.to_string_lossy().as_ref())
+            .map_err(|e| CacheError(file.clone(), e.to_string()))?;
+        paths.extend(matches.flatten().filter(|path| path.is_file()));
+    }
+    paths.sort();
+    paths.dedup();
+
+    let mut buf = vec![0; 64 * 1024];
+    for path in paths.iter() {
+        let cache_error = |e: std::io::Error| CacheError(path.display().to_string(), e.to_string());
+        let rel_path = path.strip_prefix(workspace).unwrap_or(path.as_path());
+        hasher.update(rel_path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+
+        let mut file = File::open(path).map_err(cache_error)?;
+        loop {
+            let len = file.read(&mut buf).map_err(cache_error)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+    }
+
+    Ok(!paths.is_empty())
+}
+
+// Caches are kept across runs and shared by all jobs using the same key, unlike
+// artifacts which belong to a single run
+#[derive(Clone)]
+pub struct CacheManager {
+    pub workspace: String,
+    pub root_dir: String,
+}
+
+impl CacheManager {
+    pub fn new_with_params(workspace: String, root_dir: String) -> Self {
+        Self {
+            workspace,
+            root_dir,
+        }
+    }
+
+    // Keys like `default` mean nothing outside of a workspace, so every workspace
+    // gets its own directory, named after a hash of its canonical path
+    fn get_namespace_dir(&self) -> PathBuf {
+        let workspace = fs::canonicalize(self.workspace.as_str())
+            .unwrap_or_else(|_| PathBuf::from(self.workspace.as_str()));
+        let hash = Sha256::digest(workspace.as_os_str().as_encoded_bytes());
+        Path::new(self.root_dir.as_str()).join(&format!("{:x}", hash)[..16])
+    }
+
+    // Keys become directory names, so anything that could escape the cache root
+    // is replaced
+    fn get_cache_path(&self, key: &str) -> PathBuf {
+        let mut dir_name: String = key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if dir_name.chars().all(|c| c == '.') {
+            dir_name = dir_name.replace('.', "_");
+        }
+
+        self.get_namespace_dir().join(dir_name).join(CACHE_ARCHIVE)
+    }
+
+    // File keys hash the names and contents of all matching files. Like GitLab,
+    // the key is `default` if none of them exist
+    pub fn resolve_key(&self, key: &CacheKey) -> Result<String, PipelineError> {
+        let (files, prefix) = match key {
+            CacheKey::Literal(key) => return Ok(key.clone()),
+            CacheKey::Files { files, prefix } => (files, prefix),
+        };
+
+        let mut hasher = Sha256::new();
+        let hash = if hash_files(Path::new(self.workspace.as_str()), files, &mut hasher)? {
+            format!("{:x}", hasher.finalize())
+        } else {
+            "default".to_string()
+        };
+
+        Ok(match prefix {
+            Some(prefix) => format!("{}-{}", prefix, hash),
+            None => hash,
+        })
+    }
+
+    // Restores the cache for `key` into the workspace, falling back to the
+    // fallback keys in order. Returns the key that was restored
+    pub fn restore(&self, cache: &CacheConfig, key: &str) -> Result<Option<String>, PipelineError> {
+        for key in std::iter::once(key).chain(cache.fallback_keys.iter().map(String::as_str)) {
+            let cache_path = self.get_cache_path(key);
+            if !cache_path.exists() {
+                continue;
+            }
+
+            ArtifactManager::extract_archive(
+                cache_path.as_path(),
+                CACHE_FORMAT,
+                Path::new(self.workspace.as_str()),
+            )
+            .map_err(|e| CacheError(key.to_string(), e.to_string()))?;
+            return Ok(Some(key.to_string()));
+        }
+
+        Ok(None)
+    }
+
+    // Archives the cache paths under `key` and returns the size of the archive.
+    // The archive is written to a temporary file and renamed into place, so
+    // concurrent writers don't corrupt it and jobs restoring it at the same time
+    // keep reading the previous version
+    pub fn save(&self, cache: &CacheConfig, key: &str) -> Result<u64, PipelineError> {
+        let cache_error = |e: String| CacheError(key.to_string(), e);
+        let workspace = Path::new(self.workspace.as_str());
+        let rel_paths = ArtifactManager::collect_paths(workspace, &cache.paths, &[])
+            .map_err(|e| cache_error(e.to_string()))?;
+
+        let cache_path = self.get_cache_path(key);
+        let Some(cache_dir) = cache_path.parent() else {
+            return Err(cache_error("invalid cache path".to_string()));
+        };
+        fs::create_dir_all(cache_dir).map_err(|e| cache_error(e.to_string()))?;
+        let tmp_path = cache_dir.join(format!(
+            ".{}.tmp-{}-{:?}",
+            CACHE_ARCHIVE,
+            std::process::id(),
+            std::thread::current().id()
+        ));
+
+        if let Err(e) =
+            ArtifactManager::write_archive(workspace, tmp_path.as_path(), CACHE_FORMAT, &rel_paths)
+        {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(cache_error(e.to_string()));
+        }
+        fs::rename(&tmp_path, &cache_path).map_err(|e| cache_error(e.to_string()))?;
+
+        Ok(fs::metadata(&cache_path)
+            .map_err(|e| cache_error(e.to_string()))?
+            .len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Caches of the same test share the cache root in `test_dir`
+    fn cache_manager(test_dir: &Path, workspace: &str) -> CacheManager {
+        CacheManager::new_with_params(
+            test_dir.join(workspace).display().to_string(),
+            test_dir.join("cache").display().to_string(),
+        )
+    }
+
+    #[test]
+    fn test_save_and_restore_cache() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let workspace = test_dir.join("workspace");
+        fs::create_dir_all(workspace.join(".cache/pip")).expect("should create dir");
+        fs::write(workspace.join(".cache/pip/wheel"), "wheel").expect("should write file");
+        fs::write(workspace.join("requirements.txt"), "requests==2.32.0")
+            .expect("should write file");
+
+        let cache_manager = cache_manager(test_dir, "workspace");
+        let cache = CacheConfig {
+            key: CacheKey::Files {
+                files: vec!["requirements.txt".to_string()],
+                prefix: Some("pip".to_string()),
+            },
+            paths: vec![".cache/pip".to_string()],
+            fallback_keys: vec!["pip-main".to_string()],
+            ..Default::default()
+        };
+
+        let key = cache_manager
+            .resolve_key(&cache.key)
+            .expect("should resolve key");
+        assert!(key.starts_with("pip-"));
+        assert_eq!(cache_manager.restore(&cache, key.as_str()), Ok(None));
+        cache_manager
+            .save(&cache, "pip-main")
+            .expect("saving cache should succeed");
+
+        // Changing the lockfile changes the key, so the fallback key is restored
+        fs::write(workspace.join("requirements.txt"), "requests==2.33.0")
+            .expect("should write file");
+        let new_key = cache_manager
+            .resolve_key(&cache.key)
+            .expect("should resolve key");
+        assert_ne!(key, new_key);
+
+        fs::remove_dir_all(workspace.join(".cache")).expect("should remove cache dir");
+        assert_eq!(
+            cache_manager.restore(&cache, new_key.as_str()),
+            Ok(Some("pip-main".to_string()))
+        );
+        assert_eq!(
+            fs::read_to_string(workspace.join(".cache/pip/wheel")).expect("should be restored"),
+            "wheel"
+        );
+
+        let missing = CacheKey::Files {
+            files: vec!["Cargo.lock".to_string()],
+            prefix: None,
+        };
+        assert_eq!(
+            cache_manager.resolve_key(&missing),
+            Ok("default".to_string())
+        );
+        assert_eq!(
+            cache_manager.get_cache_path("../x").parent(),
+            Some(cache_manager.get_namespace_dir().join(".._x").as_path())
+        );
+    }
+
+    #[test]
+    fn test_caches_are_kept_per_workspace() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let first_workspace = test_dir.join("first");
+        let second_workspace = test_dir.join("second");
+        fs::create_dir_all(first_workspace.join("build")).expect("should create dir");
+        fs::write(first_workspace.join("build/out"), "out").expect("should write file");
+        fs::create_dir_all(&second_workspace).expect("should create dir");
+
+        let first = cache_manager(test_dir, "first");
+        let second = cache_manager(test_dir, "second");
+        let cache = CacheConfig {
+            paths: vec!["build".to_string()],
+            ..Default::default()
+        };
+
+        let key = first.resolve_key(&cache.key).expect("should resolve key");
+        assert_eq!(key, "default");
+        first
+            .save(&cache, key.as_str())
+            .expect("saving cache should succeed");
+        assert_eq!(second.restore(&cache, key.as_str()), Ok(None));
+        assert!(!second_workspace.join("build").exists());
+
+        // The same workspace reached through another path shares its caches
+        let same = cache_manager(test_dir, "second/../first");
+        assert_eq!(
+            same.restore(&cache, key.as_str()),
+            Ok(Some("default".to_string()))
+        );
+    }
+}
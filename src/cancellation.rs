@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// 128 + SIGINT, like shells report a process interrupted with Ctrl-C
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+// How long containers get to shut down after SIGTERM before they are killed
+pub const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// Every container and network of a run carries this label, so cancelling the
+// run finds all of them, including those of services and after_script
+const RUN_LABEL: &str = "pipeline-runner.run";
+
+// Set once the pipeline is asked to stop. Shared by the signal handler and all
+// jobs of the run
+#[derive(Clone)]
+pub struct Cancellation {
+    run_id: String,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Cancellation {
+    pub fn new_with_params(run_id: String) -> Self {
+        Self {
+            run_id,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Returns whether the run had already been cancelled
+    pub fn cancel(&self) -> bool {
+        self.cancelled.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Passed to `docker run --label` and `docker network create --label`
+    pub fn label(&self) -> String {
+        format!("{}={}", RUN_LABEL, self.run_id)
+    }
+
+    // Ids of the containers (`ps`) or networks (`network ls`) of the run
+    fn list_labelled(&self, list_cmd: &[&str]) -> Vec<String> {
+        let filter = format!("label={}", self.label());
+        subprocess::Exec::cmd("docker")
+            .args(list_cmd)
+            .args(&["--quiet", "--filter", filter.as_str()])
+            .stdout(subprocess::Redirection::Pipe)
+            .stderr(subprocess::NullFile)
+            .capture()
+            .map(|capture| {
+                capture
+                    .stdout_str()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Sends SIGTERM to all running containers of the run and kills those still
+    // running after `grace_period`. Their `docker run` then returns, which ends
+    // the jobs they belong to
+    pub fn stop_containers(&self, grace_period: Duration) {
+        let containers = self.list_labelled(&["ps"]);
+        if containers.is_empty() {
+            return;
+        }
+
+        let grace_period = grace_period.as_secs().to_string();
+        let mut args = vec!["stop", "--time", grace_period.as_str()];
+        args.extend(containers.iter().map(String::as_str));
+        let _ = subprocess::Exec::cmd("docker")
+            .args(&args)
+            .stdout(subprocess::NullFile)
+            .stderr(subprocess::NullFile)
+            .join();
+    }
+
+    // Removes everything the run started. Used when the runner exits without
+    // waiting for its jobs, so nothing else cleans up after them
+    pub fn kill_containers(&self) {
+        for (list_cmd, rm_cmd) in [
+            (vec!["ps", "--all"], vec!["rm", "--force", "--volumes"]),
+            (vec!["network", "ls"], vec!["network", "rm"]),
+        ] {
+            let ids = self.list_labelled(&list_cmd);
+            if ids.is_empty() {
+                continue;
+            }
+
+            let mut args = rm_cmd;
+            args.extend(ids.iter().map(String::as_str));
+            let _ = subprocess::Exec::cmd("docker")
+                .args(&args)
+                .stdout(subprocess::NullFile)
+                .stderr(subprocess::NullFile)
+                .join();
+        }
+    }
+}
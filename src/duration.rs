@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+// Parses human readable durations like "90", "30s", "10 minutes" or "1h 30m".
+// A number without a unit is treated as seconds
+pub fn parse_duration(inp: &str) -> Result<Duration, String> {
+    let mut total_secs: u64 = 0;
+    let mut chars = inp.trim().chars().peekable();
+    let mut found_any = false;
+
+    while chars.peek().is_some() {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut number = String::new();
+        while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            number.push(*c);
+            chars.next();
+        }
+        if number.is_empty() {
+            return Err(format!("invalid duration '{}'", inp));
+        }
+
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut unit = String::new();
+        while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+            unit.push(c.to_ascii_lowercase());
+            chars.next();
+        }
+
+        let multiplier = match unit.as_str() {
+            "" | "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 60 * 60,
+            "d" | "day" | "days" => 24 * 60 * 60,
+            "w" | "wk" | "week" | "weeks" => 7 * 24 * 60 * 60,
+            _ => return Err(format!("invalid duration unit '{}' in '{}'", unit, inp)),
+        };
+
+        let number: u64 = number
+            .parse()
+            .map_err(|_| format!("invalid duration '{}'", inp))?;
+        total_secs = number
+            .checked_mul(multiplier)
+            .and_then(|secs| total_secs.checked_add(secs))
+            .ok_or_else(|| format!("duration '{}' is too long", inp))?;
+        found_any = true;
+    }
+
+    if !found_any {
+        return Err(format!("invalid duration '{}'", inp));
+    }
+
+    Ok(Duration::from_secs(total_secs))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10 minutes"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1h 30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2 Days"), Ok(Duration::from_secs(172_800)));
+        assert_eq!(parse_duration("1w"), Ok(Duration::from_secs(604_800)));
+
+        assert_eq!(parse_duration(""), Err("invalid duration ''".to_string()));
+        assert_eq!(parse_duration("m"), Err("invalid duration 'm'".to_string()));
+        assert_eq!(
+            parse_duration("5 fortnights"),
+            Err("invalid duration unit 'fortnights' in '5 fortnights'".to_string())
+        );
+        assert_eq!(
+            parse_duration("99999999999999999999"),
+            Err("invalid duration '99999999999999999999'".to_string())
+        );
+        assert_eq!(
+            parse_duration("9999999999999999w"),
+            Err("duration '9999999999999999w' is too long".to_string())
+        );
+        assert_eq!(
+            parse_duration("18446744073709551615s 1s"),
+            Err("duration '18446744073709551615s 1s' is too long".to_string())
+        );
+    }
+}
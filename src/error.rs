@@ -8,16 +8,41 @@ pub enum PipelineError {
     #[error("Failed to parse config: {0}")]
     ParsingError(String),
 
+    #[error("Job {0} not found")]
+    JobNotFound(String),
+
     #[error("Failed to execute job {0}| Reason: {1}")]
     ExecutionError(String, String),
 
     #[error("Failed to start runtime {0}")]
     RuntimeError(String),
 
+    #[error("Secret {0} could not be read: {1}")]
+    SecretError(String, String),
+
     #[error("Artifact save error: {0}")]
     ArtifactError(ArtifactError),
+
+    #[error("Cache {0} failed: {1}")]
+    CacheError(String, String),
+
+    #[error("Image {0} could not be pulled: {1}")]
+    ImagePullError(String, String),
+
+    #[error("Workspace {0} could not be used: {1}")]
+    WorkspaceError(String, String),
+
+    #[error("Mount {0} is not allowed: {1}")]
+    MountError(String, String),
+
+    #[error("Service {0} failed: {1}")]
+    ServiceError(String, String),
+
+    #[error("Pipeline was cancelled")]
+    Cancelled,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error, Eq, PartialEq)]
 pub enum ArtifactError {
     #[error("Artifact not found: {0}")]
@@ -26,6 +51,9 @@ pub enum ArtifactError {
     #[error("Artifact copy failed: {0}")]
     ArtifactCopyError(String),
 
+    #[error("Artifact is corrupted: {0}")]
+    ArtifactIntegrityError(String),
+
     #[error("Artifact cleanup failed: {0}")]
     ArtifactCleanupError(String),
 }
@@ -1,20 +1,69 @@
+use std::fs;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
 
-use crate::artifact_manager::ArtifactManager;
+use crate::artifact_manager::{ArtifactManager, format_size, unique_name};
+use crate::cache_manager::{CacheManager, hash_files};
+use crate::cancellation::Cancellation;
 use crate::error::PipelineError;
-use crate::error::PipelineError::ExecutionError;
-use crate::job::JobConfig;
+use crate::error::PipelineError::{ExecutionError, ImagePullError};
+use crate::job::{ImageConfig, JobConfig, NetworkMode, PullPolicy};
+use crate::reports::{JobReport, coverage_regex, extract_coverage, parse_dotenv, parse_junit};
+use crate::secrets::{Secret, mask_secrets};
+use crate::services::JobServices;
+
+use glob::glob;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, PartialEq)]
+enum ScriptStatus {
+    Exited(u32),
+    Signaled(u8),
+    TimedOut,
+    // The pipeline was cancelled while the script was running
+    Cancelled,
+    Unknown,
+}
+
+// Everything the scripts of a job printed, along with the last coverage found
+// in it
+struct JobOutput {
+    log: String,
+    coverage_regex: Option<Regex>,
+    coverage: Option<f64>,
+}
+
+impl JobOutput {
+    fn push_line(&mut self, line: &str) {
+        if let Some(ref regex) = self.coverage_regex
+            && let Some(coverage) = extract_coverage(regex, line)
+        {
+            self.coverage = Some(coverage);
+        }
+        self.log.push_str(line);
+        self.log.push('\n');
+    }
+}
 
 pub struct Executor {
     workspace: String,
+    secrets: Vec<Secret>,
+    cancellation: Cancellation,
 }
 
-const DEFAULT_WORKSPACE: &'static str = "./workbench";
-
 impl Executor {
-    pub fn new_with_params(workspace: Option<&str>) -> Self {
+    pub fn new_with_params(
+        workspace: String,
+        secrets: Vec<Secret>,
+        cancellation: Cancellation,
+    ) -> Self {
         Self {
-            workspace: workspace.unwrap_or(DEFAULT_WORKSPACE).to_string(),
+            workspace,
+            secrets,
+            cancellation,
         }
     }
 
@@ -22,44 +71,575 @@ impl Executor {
         &self,
         job: &JobConfig,
         artifact_manager: &ArtifactManager,
-    ) -> Result<(), PipelineError> {
+        cache_manager: &CacheManager,
+    ) -> Result<JobReport, PipelineError> {
         println!("Running job {:?}", job.name);
-        println!("Image {:?}", job.image);
+        println!("Image {:?}", job.image.name);
+        // Only `when: always` jobs start after the run was cancelled. Their
+        // failures are their own
+        let cancelled_before = self.cancellation.is_cancelled();
+        self.pull_image(job)?;
+
+        let mut output = JobOutput {
+            log: String::new(),
+            coverage_regex: match job.coverage {
+                Some(ref coverage) => Some(
+                    coverage_regex(coverage.as_str())
+                        .map_err(|e| ExecutionError(job.name.clone(), e.to_string()))?,
+                ),
+                None => None,
+            },
+            coverage: None,
+        };
+
+        let result_key = if job.cache_result {
+            match self.get_result_key(job, artifact_manager) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    println!("[{}] cache_result disabled: {}", job.name, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(ref key) = result_key {
+            match artifact_manager.restore_result(job.name.as_str(), key.as_str()) {
+                Ok(Some(result)) => {
+                    for line in result.log.lines() {
+                        println!("[{}] | {}", job.name, line);
+                        output.push_line(line);
+                    }
+                    println!("[{}] CACHED", job.name);
+                    let mut report = self.read_reports(job, &result.reports);
+                    report.coverage = self.report_coverage(job, &output);
+                    return Ok(report);
+                }
+                Ok(None) => {}
+                Err(e) => println!("[{}] cache_result could not be restored: {}", job.name, e),
+            }
+        }
 
-        if let Some(ref needs) = job.needs {
-            for job_name in needs {
+        if let Some(ref dependencies) = job.dependencies {
+            for job_name in dependencies {
                 artifact_manager
                     .load_artifacts(job_name.as_str(), job.name.as_str())
-                    .map_err(|e| PipelineError::ArtifactError(e))?;
+                    .map_err(PipelineError::ArtifactError)?;
+            }
+        }
+
+        // Keys are resolved once before the script, since it might change the
+        // files they hash. Cache failures never fail the job
+        let mut cache_keys = vec![];
+        for cache in job.cache.iter() {
+            let key = match cache_manager.resolve_key(&cache.key) {
+                Ok(key) => key,
+                Err(e) => {
+                    println!("[{}] CACHE: {}", job.name, e);
+                    continue;
+                }
+            };
+            if cache.policy.pulls() {
+                match cache_manager.restore(cache, key.as_str()) {
+                    Ok(Some(restored_key)) => {
+                        println!("[{}] CACHE: restored {}", job.name, restored_key)
+                    }
+                    Ok(None) => println!("[{}] CACHE: no cache for {}", job.name, key),
+                    Err(e) => println!("[{}] CACHE: {}", job.name, e),
+                }
+            }
+            cache_keys.push((cache, key));
+        }
+
+        let mut script = job.before_script.clone();
+        script.extend(job.script.iter().cloned());
+        let merged_script = script.join(" && ");
+        let container_name =
+            Self::get_container_name(artifact_manager.run_id.as_str(), job.name.as_str());
+
+        // Services stay up for all attempts and after_script
+        let services = if job.services.is_empty() {
+            None
+        } else {
+            Some(JobServices::start(
+                job,
+                container_name.as_str(),
+                self.cancellation.label().as_str(),
+            )?)
+        };
+        let network = services.as_ref().map(JobServices::network);
+
+        // Checked before every container is started too, since cancelling only
+        // stops the containers that are already running
+        let cancelled = || !cancelled_before && self.cancellation.is_cancelled();
+        let mut attempt = 0;
+        let status = loop {
+            let mut status = if cancelled() {
+                ScriptStatus::Cancelled
+            } else {
+                self.run_script(
+                    job,
+                    merged_script.as_str(),
+                    container_name.as_str(),
+                    network,
+                    &mut output,
+                )?
+            };
+            if status != ScriptStatus::Exited(0) && cancelled() {
+                status = ScriptStatus::Cancelled;
+            }
+
+            // after_script runs in its own container and never changes the job result
+            if !job.after_script.is_empty() && !cancelled() {
+                let after_script = job.after_script.join(" && ");
+                let after_container_name = format!("{}-after-script", container_name);
+                match self.run_script(
+                    job,
+                    after_script.as_str(),
+                    after_container_name.as_str(),
+                    network,
+                    &mut output,
+                ) {
+                    Ok(ScriptStatus::Exited(0)) => {}
+                    Ok(after_status) => {
+                        println!("[{}] after_script failed: {:?}", job.name, after_status)
+                    }
+                    Err(e) => println!("[{}] after_script failed: {}", job.name, e),
+                }
+            }
+
+            if matches!(status, ScriptStatus::Exited(0) | ScriptStatus::Cancelled)
+                || attempt >= job.retry
+            {
+                break status;
+            }
+            attempt += 1;
+            println!("[{}] RETRYING ({}/{})", job.name, attempt, job.retry);
+        };
+        drop(services);
+
+        match status {
+            ScriptStatus::Exited(0) => println!("[{}] SUCCESS", job.name.clone()),
+            ScriptStatus::Exited(code) => {
+                println!("[{}] FAILURE CODE: {}", job.name.clone(), code);
+            }
+            ScriptStatus::Signaled(signal_num) => {
+                println!("[{}] KILLED SIGNAL: {}", job.name.clone(), signal_num);
+            }
+            ScriptStatus::TimedOut => {
+                println!(
+                    "[{}] TIMED OUT after {:?}",
+                    job.name.clone(),
+                    job.timeout.unwrap_or_default()
+                );
+            }
+            ScriptStatus::Cancelled => println!("[{}] CANCELLED", job.name),
+            ScriptStatus::Unknown => println!("Unknown exit status"),
+        }
+
+        if status == ScriptStatus::Exited(0) {
+            for (cache, key) in cache_keys.iter().filter(|(cache, _)| cache.policy.pushes()) {
+                match cache_manager.save(cache, key.as_str()) {
+                    Ok(size) => println!(
+                        "[{}] CACHE: saved {} ({})",
+                        job.name,
+                        key,
+                        format_size(size)
+                    ),
+                    Err(e) => println!("[{}] CACHE: {}", job.name, e),
+                }
+            }
+        }
+
+        // Reports are read whatever the result, since failing tests are what
+        // they are most useful for
+        let report_paths = self.find_reports(job);
+        let mut report = self.read_reports(job, &report_paths);
+        report.coverage = self.report_coverage(job, &output);
+
+        // Dependent jobs and cached results rely on the declared artifacts, so
+        // failing to save them fails the job
+        if let Some(ref artifacts) = job.artifacts
+            && !artifacts.paths.is_empty()
+            && artifacts.when.matches(status == ScriptStatus::Exited(0))
+        {
+            let size = artifact_manager
+                .save_artifacts(job.name.as_str(), artifacts)
+                .map_err(|e| {
+                    ExecutionError(
+                        job.name.clone(),
+                        format!("artifacts could not be saved: {}", e),
+                    )
+                })?;
+            println!(
+                "[{}] ARTIFACTS: {} ({})",
+                job.name,
+                format_size(size),
+                artifacts.format.name()
+            );
+        }
+
+        if let Some(ref artifacts) = job.artifacts
+            && let Some(ref dotenv) = artifacts.reports.dotenv
+            && status == ScriptStatus::Exited(0)
+        {
+            self.save_dotenv(job, dotenv.as_str(), artifact_manager)?;
+        }
+
+        if let Some(key) = result_key
+            && status == ScriptStatus::Exited(0)
+            && let Err(e) = artifact_manager.save_result(
+                job.name.as_str(),
+                key.as_str(),
+                &output.log,
+                &report_paths,
+            )
+        {
+            println!("[{}] cache_result could not be saved: {}", job.name, e);
+        }
+
+        Ok(report)
+    }
+
+    // Dependent jobs can't run without the variables of a dotenv report, so
+    // failing to read it is an error
+    fn save_dotenv(
+        &self,
+        job: &JobConfig,
+        dotenv: &str,
+        artifact_manager: &ArtifactManager,
+    ) -> Result<(), PipelineError> {
+        let path = Path::new(self.workspace.as_str()).join(dotenv);
+        let variables = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| parse_dotenv(contents.as_str()))
+            .map_err(|e| {
+                ExecutionError(
+                    job.name.clone(),
+                    format!("dotenv report {} could not be read: {}", dotenv, e),
+                )
+            })?;
+        artifact_manager
+            .save_dotenv(job.name.as_str(), &variables)
+            .map_err(PipelineError::ArtifactError)?;
+        println!("[{}] DOTENV: {} variables", job.name, variables.len());
+        Ok(())
+    }
+
+    fn report_coverage(&self, job: &JobConfig, output: &JobOutput) -> Option<f64> {
+        if job.coverage.is_some() {
+            match output.coverage {
+                Some(coverage) => println!("[{}] COVERAGE: {}%", job.name, coverage),
+                None => println!("[{}] COVERAGE: not found in the job output", job.name),
             }
         }
+        output.coverage
+    }
 
-        let merged_script = job.script.join(" && ");
+    fn find_reports(&self, job: &JobConfig) -> Vec<PathBuf> {
+        let Some(ref artifacts) = job.artifacts else {
+            return vec![];
+        };
 
-        let cmd = vec![
+        let workspace = Path::new(self.workspace.as_str());
+        let mut report_paths = vec![];
+        for pattern in artifacts.reports.junit.iter() {
+            let paths: Vec<PathBuf> = glob(workspace.join(pattern).to_string_lossy().as_ref())
+                .map(|paths| paths.flatten().collect())
+                .unwrap_or_default();
+            if paths.is_empty() {
+                println!("[{}] junit report {} not found", job.name, pattern);
+            }
+            report_paths.extend(paths);
+        }
+        report_paths
+    }
+
+    fn read_reports(&self, job: &JobConfig, paths: &[PathBuf]) -> JobReport {
+        let mut report = JobReport::new_with_params(job.name.clone());
+        let Some(ref artifacts) = job.artifacts else {
+            return report;
+        };
+
+        for path in paths {
+            match fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|xml| parse_junit(xml.as_str()))
+            {
+                Ok(suites) => report.test_suites.extend(suites),
+                Err(e) => println!(
+                    "[{}] junit report {} could not be read: {}",
+                    job.name,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        if !artifacts.reports.junit.is_empty() {
+            let counts = report.test_counts();
+            println!(
+                "[{}] TESTS: {} passed, {} failed, {} skipped",
+                job.name, counts.passed, counts.failed, counts.skipped
+            );
+        }
+        report
+    }
+
+    // Resource limits and security options of the job container
+    fn get_container_options(job: &JobConfig) -> Vec<String> {
+        let mut options = vec![];
+        if let Some(cpus) = job.resources.cpus {
+            options.extend(["--cpus".to_string(), cpus.to_string()]);
+        }
+        if let Some(memory) = job.resources.memory {
+            options.extend(["--memory".to_string(), memory.to_string()]);
+        }
+        if job.network == NetworkMode::None {
+            options.extend(["--network".to_string(), "none".to_string()]);
+        }
+        // Most tools need somewhere to write temporary files
+        if job.read_only {
+            options.extend([
+                "--read-only".to_string(),
+                "--tmpfs".to_string(),
+                "/tmp".to_string(),
+            ]);
+        }
+        for capability in job.cap_drop.iter() {
+            options.extend(["--cap-drop".to_string(), capability.clone()]);
+        }
+        if job.run_as_host_user {
+            // SAFETY: getuid and getgid can't fail and have no side effects
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            options.extend(["--user".to_string(), format!("{}:{}", uid, gid)]);
+        }
+        options
+    }
+
+    // The after_script and service containers of a job use this name as a
+    // prefix, so it has to be unique per job
+    fn get_container_name(run_id: &str, job_name: &str) -> String {
+        format!("pipeline-{}-{}", run_id, unique_name(job_name))
+    }
+
+    fn image_exists(image: &ImageConfig) -> bool {
+        subprocess::Exec::cmd("docker")
+            .args(&["image", "inspect", image.name.as_str()])
+            .stdout(subprocess::NullFile)
+            .stderr(subprocess::NullFile)
+            .join()
+            .is_ok_and(|status| status.success())
+    }
+
+    // Images are pulled here according to their pull policy, so `docker run`
+    // never pulls and a failing pull isn't mistaken for a failing script
+    fn pull_image(&self, job: &JobConfig) -> Result<(), PipelineError> {
+        let image = &job.image;
+        let pull_error = |e: String| ImagePullError(image.name.clone(), e);
+        match image.pull_policy {
+            PullPolicy::Never if Self::image_exists(image) => return Ok(()),
+            PullPolicy::Never => {
+                return Err(pull_error(
+                    "it isn't available locally and pull_policy is never".to_string(),
+                ));
+            }
+            PullPolicy::IfNotPresent if Self::image_exists(image) => return Ok(()),
+            PullPolicy::IfNotPresent | PullPolicy::Always => {}
+        }
+
+        println!("[{}] PULLING {}", job.name, image.name);
+        let mut args = vec!["pull", "--quiet"];
+        if let Some(ref platform) = image.platform {
+            args.extend(["--platform", platform.as_str()]);
+        }
+        args.push(image.name.as_str());
+        let capture = subprocess::Exec::cmd("docker")
+            .args(&args)
+            .stdout(subprocess::Redirection::Pipe)
+            .stderr(subprocess::Redirection::Merge)
+            .capture()
+            .map_err(|e| pull_error(e.to_string()))?;
+        if !capture.success() {
+            return Err(pull_error(capture.stdout_str().trim().to_string()));
+        }
+        Ok(())
+    }
+
+    // The image is identified by its id rather than its tag, since tags like
+    // `latest` move. It has been pulled by the time this runs
+    fn get_image_id(image: &str) -> Option<String> {
+        subprocess::Exec::cmd("docker")
+            .args(&["image", "inspect", "--format", "{{.Id}}", image])
+            .stdout(subprocess::Redirection::Pipe)
+            .stderr(subprocess::NullFile)
+            .capture()
+            .ok()
+            .filter(|capture| capture.success())
+            .map(|capture| capture.stdout_str().trim().to_string())
+    }
+
+    // Hashes everything the result of a job depends on: its image, scripts,
+    // variables, artifacts config, services and mounts, the input files in the
+    // workspace and the artifacts it loads from other jobs
+    fn get_result_key(
+        &self,
+        job: &JobConfig,
+        artifact_manager: &ArtifactManager,
+    ) -> Result<String, PipelineError> {
+        let Some(image_id) = Self::get_image_id(job.image.name.as_str()) else {
+            return Err(ExecutionError(
+                job.name.clone(),
+                format!("image {} could not be inspected", job.image.name),
+            ));
+        };
+
+        // Results are stored outside the workspace, so the same job in another
+        // checkout has to get another key
+        let workspace = fs::canonicalize(self.workspace.as_str())
+            .map_err(|e| ExecutionError(job.name.clone(), e.to_string()))?;
+        let mut hasher = Sha256::new();
+        hasher.update(workspace.as_os_str().as_encoded_bytes());
+        hasher.update([1]);
+        let mut variables: Vec<String> = job
+            .variables
+            .iter()
+            .map(|v| format!("{}={}", v.key, v.value))
+            .collect();
+        variables.sort();
+        for part in [
+            vec![job.name.clone(), image_id, format!("{:?}", job.image)],
+            job.before_script.clone(),
+            job.script.clone(),
+            job.after_script.clone(),
+            variables,
+            vec![format!("{:?}", job.artifacts)],
+            vec![format!("{:?}", job.services), format!("{:?}", job.mounts)],
+        ] {
+            for elem in part {
+                hasher.update(elem);
+                hasher.update([0]);
+            }
+            hasher.update([1]);
+        }
+
+        hash_files(workspace.as_path(), &job.inputs, &mut hasher)?;
+
+        for dependency in job.dependencies.iter().flatten() {
+            let artifacts_hash = artifact_manager
+                .artifacts_hash(dependency.as_str())
+                .map_err(PipelineError::ArtifactError)?;
+            hasher.update(dependency);
+            hasher.update(artifacts_hash.unwrap_or_default());
+            hasher.update([0]);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    // Runs `script` in a new container of the job's image and streams its output,
+    // which is also appended to `output`
+    fn run_script(
+        &self,
+        job: &JobConfig,
+        script: &str,
+        container_name: &str,
+        network: Option<&str>,
+        output: &mut JobOutput,
+    ) -> Result<ScriptStatus, PipelineError> {
+        let mut cmd = vec![
             "docker".to_string(),
             "run".to_string(),
             "--rm".to_string(),
+            "--name".to_string(),
+            container_name.to_string(),
+            "--label".to_string(),
+            self.cancellation.label(),
             "-v".to_string(),
             format!("{}:/workspace", self.workspace),
             "-w".to_string(),
             "/workspace".to_string(),
-            job.image.clone(),
-            "sh".to_string(),
-            "-c".to_string(),
-            merged_script,
+            "--pull".to_string(),
+            "never".to_string(),
         ];
+        if let Some(ref platform) = job.image.platform {
+            cmd.push("--platform".to_string());
+            cmd.push(platform.clone());
+        }
+        if let Some(ref user) = job.image.user {
+            cmd.push("--user".to_string());
+            cmd.push(user.clone());
+        }
+        cmd.extend(Self::get_container_options(job));
+        for mount in job.mounts.iter() {
+            let mut mount_option =
+                format!("type=bind,source={},target={}", mount.source, mount.target);
+            if mount.read_only {
+                mount_option.push_str(",readonly");
+            }
+            cmd.push("--mount".to_string());
+            cmd.push(mount_option);
+        }
+        if let Some(network) = network {
+            cmd.push("--network".to_string());
+            cmd.push(network.to_string());
+        }
+
+        for var in &job.variables {
+            cmd.push("-e".to_string());
+            cmd.push(format!("{}={}", var.key, var.value));
+        }
+
+        // Secrets are passed by name only so their values never show up in the
+        // docker command line. Docker picks the values up from our environment
+        let mut env = subprocess::PopenConfig::current_env();
+        for secret in &self.secrets {
+            cmd.push("-e".to_string());
+            cmd.push(secret.key.clone());
+            env.retain(|(key, _)| key.as_os_str() != secret.key.as_str());
+            env.push((secret.key.clone().into(), secret.value.clone().into()));
+        }
+
+        // Docker only takes the executable as --entrypoint, its arguments go
+        // after the image
+        let entrypoint = job.image.entrypoint.as_deref().unwrap_or_default();
+        if let Some((executable, _)) = entrypoint.split_first() {
+            cmd.push("--entrypoint".to_string());
+            cmd.push(executable.clone());
+        }
+        cmd.push(job.image.name.clone());
+        cmd.extend(entrypoint.iter().skip(1).cloned());
+        cmd.extend(["sh".to_string(), "-c".to_string(), script.to_string()]);
 
         let mut process = subprocess::Popen::create(
             cmd.as_slice(),
             subprocess::PopenConfig {
                 stdout: subprocess::Redirection::Pipe,
                 stderr: subprocess::Redirection::Merge,
+                env: Some(env),
                 ..Default::default()
             },
         )
         .map_err(|e| ExecutionError(job.name.clone(), e.to_string()))?;
 
+        // The watchdog kills the container once the timeout expires. Dropping
+        // `done_tx` when the script finishes stops it
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let watchdog = job.timeout.map(|timeout| {
+            let container_name = container_name.to_string();
+            thread::spawn(move || {
+                if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+                    let _ = subprocess::Exec::cmd("docker")
+                        .args(&["kill", container_name.as_str()])
+                        .stdout(subprocess::NullFile)
+                        .stderr(subprocess::NullFile)
+                        .join();
+                    return true;
+                }
+                false
+            })
+        });
+
         let out_fd = process
             .stdout
             .as_ref()
@@ -69,7 +649,9 @@ impl Executor {
 
         for line in reader.lines() {
             if let Ok(line) = line {
+                let line = mask_secrets(&line, &self.secrets);
                 println!("[{}] | {}", &job.name, line);
+                output.push_line(line.as_str());
             } else {
                 println!("Error reading output. Program may exit unexpectedly");
             }
@@ -79,36 +661,26 @@ impl Executor {
             .wait()
             .map_err(|e| ExecutionError(job.name.clone(), e.to_string()))?;
 
-        match process.exit_status() {
-            None => println!(
-                "Unexpected error: Process failed to terminate. You may need to manually kill it"
-            ),
-            Some(exit_status) => match exit_status {
-                subprocess::ExitStatus::Exited(code) => {
-                    if code == 0 {
-                        println!("[{}] SUCCESS", job.name.clone());
-
-                        if let Some(ref artifacts) = job.artifacts {
-                            let artifacts: Vec<String> = artifacts
-                                .iter()
-                                .map(|a| format!("{}/{}", self.workspace, a))
-                                .collect();
-                            let artifacts = artifacts.iter().map(|a| a.as_str()).collect();
-                            artifact_manager
-                                .save_artifacts(job.name.as_str(), artifacts)
-                                .map_err(|e| PipelineError::ArtifactError(e))?;
-                        }
-                    } else {
-                        println!("[{}] FAILURE CODE: {}", job.name.clone(), code);
-                    }
-                }
-                subprocess::ExitStatus::Signaled(signal_num) => {
-                    println!("[{}] KILLED SIGNAL: {}", job.name.clone(), signal_num);
-                }
-                _ => println!("Unknown exit status"),
-            },
+        drop(done_tx);
+        let timed_out = watchdog.is_some_and(|w| w.join().unwrap_or(false));
+        if timed_out {
+            return Ok(ScriptStatus::TimedOut);
         }
 
-        Ok(())
+        let status = match process.exit_status() {
+            None => {
+                println!(
+                    "Unexpected error: Process failed to terminate. You may need to manually kill it"
+                );
+                ScriptStatus::Unknown
+            }
+            Some(subprocess::ExitStatus::Exited(code)) => ScriptStatus::Exited(code),
+            Some(subprocess::ExitStatus::Signaled(signal_num)) => {
+                ScriptStatus::Signaled(signal_num)
+            }
+            Some(_) => ScriptStatus::Unknown,
+        };
+
+        Ok(status)
     }
 }
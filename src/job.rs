@@ -1,21 +1,389 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, PartialEq, Clone)]
-pub struct JobConfig {
+pub struct Variable {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum ArtifactFormat {
+    // Files are kept individually in a content addressed store
+    #[default]
+    #[serde(rename = "files")]
+    Files,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+}
+
+impl ArtifactFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        [Self::Files, Self::Tar, Self::TarGz, Self::TarZst]
+            .into_iter()
+            .find(|f| f.name() == format)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Files => "files",
+            Self::Tar => "tar",
+            Self::TarGz => "tar.gz",
+            Self::TarZst => "tar.zst",
+        }
+    }
+
+    pub fn archive_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Files => None,
+            Self::Tar => Some("artifacts.tar"),
+            Self::TarGz => Some("artifacts.tar.gz"),
+            Self::TarZst => Some("artifacts.tar.zst"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Need {
+    pub job: String,
+    // Whether the artifacts of `job` are loaded before running
+    pub artifacts: bool,
+    // Optional needs on jobs that don't exist in the pipeline are ignored
+    pub optional: bool,
+}
+
+impl Need {
+    pub fn new_with_params(job: String, artifacts: bool, optional: bool) -> Self {
+        Self {
+            job,
+            artifacts,
+            optional,
+        }
+    }
+}
+
+// Decides for which job results artifacts are saved
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ArtifactsWhen {
+    #[default]
+    OnSuccess,
+    OnFailure,
+    Always,
+}
+
+impl ArtifactsWhen {
+    pub fn parse(when: &str) -> Option<Self> {
+        match when {
+            "on_success" => Some(Self::OnSuccess),
+            "on_failure" => Some(Self::OnFailure),
+            "always" => Some(Self::Always),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, success: bool) -> bool {
+        match self {
+            Self::OnSuccess => success,
+            Self::OnFailure => !success,
+            Self::Always => true,
+        }
+    }
+}
+
+// Files a job produces that the runner interprets itself
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ArtifactsReports {
+    // Paths or globs of JUnit XML reports, relative to the workspace
+    pub junit: Vec<String>,
+    // A file of KEY=VALUE lines whose variables are passed on to the jobs
+    // depending on this one
+    pub dotenv: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ArtifactsConfig {
+    // Paths or globs relative to the workspace
+    pub paths: Vec<String>,
+    // Globs of paths that are left out even if they match `paths`
+    pub exclude: Vec<String>,
+    pub format: ArtifactFormat,
+    pub when: ArtifactsWhen,
+    // Saved artifacts are deleted by `prune` once this has passed. They are
+    // kept forever if not set
+    pub expire_in: Option<Duration>,
+    pub reports: ArtifactsReports,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CacheKey {
+    Literal(String),
+    // Hashes the contents of lockfiles like `requirements.txt`, so the cache is
+    // rebuilt whenever they change
+    Files {
+        files: Vec<String>,
+        prefix: Option<String>,
+    },
+}
+
+impl Default for CacheKey {
+    fn default() -> Self {
+        Self::Literal("default".to_string())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum CachePolicy {
+    Pull,
+    Push,
+    #[default]
+    PullPush,
+}
+
+impl CachePolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "pull" => Some(Self::Pull),
+            "push" => Some(Self::Push),
+            "pull-push" => Some(Self::PullPush),
+            _ => None,
+        }
+    }
+
+    pub fn pulls(&self) -> bool {
+        matches!(self, Self::Pull | Self::PullPush)
+    }
+
+    pub fn pushes(&self) -> bool {
+        matches!(self, Self::Push | Self::PullPush)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CacheConfig {
+    pub key: CacheKey,
+    // Paths or globs relative to the workspace
+    pub paths: Vec<String>,
+    pub policy: CachePolicy,
+    // Keys that are tried in order when there is no cache for `key` yet
+    pub fallback_keys: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum PullPolicy {
+    Always,
+    // Docker's own behaviour
+    #[default]
+    IfNotPresent,
+    Never,
+}
+
+impl PullPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "always" => Some(Self::Always),
+            "if-not-present" => Some(Self::IfNotPresent),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ImageConfig {
     pub name: String,
+    // Replaces the entrypoint of the image. `[""]` removes it
+    pub entrypoint: Option<Vec<String>>,
+    pub pull_policy: PullPolicy,
+    pub platform: Option<String>,
+    // User the scripts run as instead of the image's default
+    pub user: Option<String>,
+}
+
+impl ImageConfig {
+    pub fn new_with_params(name: String) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ResourcesConfig {
+    // Number of CPUs the job may use, e.g. 1.5
+    pub cpus: Option<f64>,
+    // Memory limit in bytes
+    pub memory: Option<u64>,
+}
+
+impl ResourcesConfig {
+    // Parses memory sizes the way docker does: a number of bytes optionally
+    // followed by k, m or g, e.g. 512m or 2gb
+    pub fn parse_memory(memory: &str) -> Option<u64> {
+        let memory = memory.trim().to_ascii_lowercase();
+        let memory = memory.strip_suffix('b').unwrap_or(memory.as_str());
+        let (number, shift) = match memory.char_indices().last() {
+            Some((i, 'k')) => (&memory[..i], 10),
+            Some((i, 'm')) => (&memory[..i], 20),
+            Some((i, 'g')) => (&memory[..i], 30),
+            _ => (memory, 0),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(1 << shift))
+            .filter(|memory| *memory > 0)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum NetworkMode {
+    #[default]
+    Bridge,
+    // No network at all, not even for services
+    None,
+}
+
+impl NetworkMode {
+    pub fn parse(network: &str) -> Option<Self> {
+        match network {
+            "bridge" => Some(Self::Bridge),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+}
+
+// A container started next to the job, e.g. a database for integration tests
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Service {
     pub image: String,
+    // Host name the job reaches the service by
+    pub alias: String,
+    // Replaces the command of the image if not empty
+    pub command: Vec<String>,
+    pub variables: Vec<Variable>,
+}
+
+impl Service {
+    // Like GitLab, the alias defaults to the image name without its tag, with
+    // slashes replaced by dashes. `registry.local/tutum/wordpress:6` becomes
+    // `registry.local-tutum-wordpress`. Anything else that isn't allowed in host
+    // names, like the `:` of a registry port, becomes a dash too
+    pub fn default_alias(image: &str) -> String {
+        let name = image.split('@').next().unwrap_or(image);
+        let name = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => name,
+            _ => name,
+        };
+        name.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_' {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum JobWhen {
+    #[default]
+    OnSuccess,
+    // Runs even if the pipeline was cancelled, e.g. to clean up
+    Always,
+}
+
+impl JobWhen {
+    pub fn parse(when: &str) -> Option<Self> {
+        match when {
+            "on_success" => Some(Self::OnSuccess),
+            "always" => Some(Self::Always),
+            _ => None,
+        }
+    }
+}
+
+// A host path bind mounted into the job container
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mount {
+    pub source: String,
+    pub target: String,
+    pub read_only: bool,
+}
+
+impl Mount {
+    // Mounts are read only unless a job asks otherwise
+    pub fn new_with_params(source: String, target: String) -> Self {
+        Self {
+            source,
+            target,
+            read_only: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct JobConfig {
+    pub name: String,
+    pub image: ImageConfig,
     pub stage: Option<String>,
     pub script: Vec<String>,
-    pub needs: Option<Vec<String>>,
-    pub artifacts: Option<Vec<String>>,
+    pub needs: Option<Vec<Need>>,
+    // Jobs whose artifacts are loaded. Defaults to the jobs of earlier stages,
+    // or the needed jobs if there are any
+    pub dependencies: Option<Vec<String>>,
+    pub artifacts: Option<ArtifactsConfig>,
+    // Restored before the script runs and saved after it succeeded
+    pub cache: Vec<CacheConfig>,
+    // Started before the job and removed once it finished
+    pub services: Vec<Service>,
+    pub resources: ResourcesConfig,
+    pub network: NetworkMode,
+    // Only the workspace and /tmp are writable
+    pub read_only: bool,
+    // Linux capabilities removed from the container, e.g. ALL
+    pub cap_drop: Vec<String>,
+    // Runs the scripts as the user running the pipeline, so files they leave in
+    // the workspace aren't owned by root
+    pub run_as_host_user: bool,
+    // Host paths mounted next to the workspace, e.g. shared toolchains
+    pub mounts: Vec<Mount>,
+    pub when: JobWhen,
+    // Skips the job and reuses the result of an earlier successful run when
+    // nothing it depends on changed
+    pub cache_result: bool,
+    // Globs of the files in the workspace the job's result depends on
+    pub inputs: Vec<String>,
+    // Regex whose last match in the job output is the job's test coverage
+    pub coverage: Option<String>,
+    // Runs in the same shell as `script`, right before it
+    pub before_script: Vec<String>,
+    // Runs in a separate container after `script`, even if it failed
+    pub after_script: Vec<String>,
+    pub timeout: Option<Duration>,
+    // Number of times the job is retried after a failure
+    pub retry: u32,
+    // Job level variables. These take precedence over the global ones and are
+    // exported to the job's environment
+    pub variables: Vec<Variable>,
 }
 
 impl JobConfig {
     pub fn new_with_params(
         name: String,
-        image: String,
+        image: ImageConfig,
         stage: Option<String>,
         script: Vec<String>,
-        needs: Option<Vec<String>>,
-        artifacts: Option<Vec<String>>,
+        needs: Option<Vec<Need>>,
+        artifacts: Option<ArtifactsConfig>,
     ) -> Self {
         Self {
             name,
@@ -24,6 +392,7 @@ impl JobConfig {
             script,
             needs,
             artifacts,
+            ..Default::default()
         }
     }
 }
@@ -1,24 +1,141 @@
 mod artifact_manager;
+mod cache_manager;
+mod cancellation;
+mod duration;
 mod error;
 mod executor;
 mod job;
 mod pipeline;
+mod reports;
+mod secrets;
+mod services;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(long)]
+    file_path: Option<String>,
+
+    /// File with KEY=VALUE lines that are passed to every job as secret env variables
+    #[arg(long)]
+    secrets_file: Option<String>,
+
+    /// Keep the artifacts of the run after it finishes so they can be listed and retrieved later
     #[arg(long)]
-    file_path: String,
+    keep_artifacts: bool,
+
+    /// Where the JUnit reports of all jobs are merged into
+    #[arg(long, default_value = "junit-report.xml")]
+    junit_report: String,
+
+    /// Write the coverage and test counts of every job to this file as JSON
+    #[arg(long)]
+    summary_json: Option<String>,
+
+    /// Directory mounted into every job, overriding `workspace` in the pipeline file
+    #[arg(long)]
+    workspace: Option<String>,
+
+    /// Host directory jobs may mount paths from with `mounts`. Can be repeated
+    #[arg(long = "mount-root")]
+    mount_roots: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the config of a job after `default` and `extends` are applied
+    Show { job: String },
+    /// Delete artifacts whose `expire_in` has passed, results unused for a week and
+    /// blobs no artifacts use anymore
+    Prune,
+    /// Inspect the artifacts of runs started with --keep-artifacts
+    Artifacts {
+        #[command(subcommand)]
+        command: ArtifactsCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ArtifactsCommand {
+    /// List runs, the jobs of a run or the artifacts of a job
+    List {
+        run: Option<String>,
+        job: Option<String>,
+    },
+    /// Copy the artifacts of a job into a directory
+    Get {
+        run: String,
+        job: String,
+        #[arg(long, default_value = ".")]
+        output: String,
+    },
+}
+
+// Running or showing jobs needs a pipeline file, managing artifacts doesn't
+fn new_pipeline(args: Args) -> Option<pipeline::Pipeline> {
+    let Some(file_path) = args.file_path else {
+        println!("--file-path is required");
+        return None;
+    };
+    Some(pipeline::Pipeline::new_with_params(
+        file_path,
+        args.secrets_file,
+        args.keep_artifacts,
+        args.junit_report,
+        args.summary_json,
+        args.workspace,
+        args.mount_roots,
+    ))
 }
 
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
 
-    let executor = pipeline::Pipeline::new_with_params(args.file_path);
-    match executor.run() {
-        Ok(_) => println!("Execution completed successfully"),
-        Err(e) => println!("Execution failed Error: {:?}", e),
+    match args.command.take() {
+        Some(Command::Show { job }) => {
+            let Some(executor) = new_pipeline(args) else {
+                return;
+            };
+            if let Err(e) = executor.show(job.as_str()) {
+                println!("Show failed Error: {:?}", e);
+            }
+        }
+        Some(Command::Prune) => {
+            if let Err(e) = pipeline::Pipeline::prune() {
+                println!("Prune failed Error: {:?}", e);
+            }
+        }
+        Some(Command::Artifacts {
+            command: ArtifactsCommand::List { run, job },
+        }) => {
+            if let Err(e) = pipeline::Pipeline::list_artifacts(run.as_deref(), job.as_deref()) {
+                println!("Listing artifacts failed Error: {:?}", e);
+            }
+        }
+        Some(Command::Artifacts {
+            command: ArtifactsCommand::Get { run, job, output },
+        }) => {
+            if let Err(e) = pipeline::Pipeline::get_artifacts(&run, &job, &output) {
+                println!("Getting artifacts failed Error: {:?}", e);
+            }
+        }
+        None => {
+            let Some(executor) = new_pipeline(args) else {
+                return;
+            };
+            match executor.run() {
+                Ok(_) => println!("Execution completed successfully"),
+                Err(error::PipelineError::Cancelled) => {
+                    println!("Execution cancelled");
+                    std::process::exit(cancellation::CANCELLED_EXIT_CODE);
+                }
+                Err(e) => println!("Execution failed Error: {:?}", e),
+            }
+        }
     }
 }
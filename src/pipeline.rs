@@ -1,27 +1,74 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use glob::{Pattern, glob};
 use tokio::runtime::Runtime;
+use tokio::signal::unix::{SignalKind, signal};
 
-use crate::artifact_manager::ArtifactManager;
+use crate::artifact_manager::{ArtifactManager, format_size, new_run_id};
+use crate::cache_manager::CacheManager;
+use crate::cancellation::{CANCEL_GRACE_PERIOD, CANCELLED_EXIT_CODE, Cancellation};
+use crate::duration::parse_duration;
 use crate::error::PipelineError;
-use crate::error::PipelineError::{ConfigFileNotReadable, ParsingError, RuntimeError};
+use crate::error::PipelineError::{
+    Cancelled, ConfigFileNotReadable, JobNotFound, MountError, ParsingError, RuntimeError,
+    WorkspaceError,
+};
 use crate::executor::Executor;
-use crate::job::JobConfig;
+use crate::job::{
+    ArtifactFormat, ArtifactsConfig, ArtifactsWhen, CacheConfig, CacheKey, CachePolicy,
+    ImageConfig, JobConfig, JobWhen, Mount, Need, NetworkMode, PullPolicy, ResourcesConfig,
+    Service, Variable,
+};
+use crate::reports::{
+    JobReport, TestCase, TestCounts, TestStatus, coverage_regex, pipeline_coverage, write_junit,
+    write_summary,
+};
+use crate::secrets::{Secret, SecretFile, load_secrets_file, warn_unmasked_secrets};
 
-const DEFAULT_WORKSPACE: &'static str = "./workbench";
-const DEFAULT_ARTIFACT_LOCATION: &'static str = "/tmp/.pipeline_artifacts";
+const DEFAULT_WORKSPACE: &str = "./workbench";
+// Where the workspace is mounted in job containers
+const CONTAINER_WORKSPACE: &str = "/workspace";
+const DEFAULT_ARTIFACT_LOCATION: &str = "/tmp/.pipeline_artifacts";
+const CACHE_DIR: &str = "pipeline-runner/cache";
 
-#[derive(Debug, PartialEq)]
-pub struct Variable {
-    key: String,
-    value: String,
-}
+// Upper bound for `parallel: N` and the cells of a matrix, so a typo can't
+// spawn thousands of jobs
+const MAX_PARALLEL_JOBS: u64 = 200;
+
+// How deep includes may be nested before we give up on a config
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+// Top level keys that configure the pipeline instead of defining a job
+const PIPELINE_KEYS: [&str; 5] = ["stages", "variables", "secrets", "workspace", "default"];
+
+// Keys of the top level `default` section that are inherited by every job which
+// doesn't set them itself
+const DEFAULT_KEYS: [&str; 13] = [
+    "image",
+    "before_script",
+    "after_script",
+    "timeout",
+    "retry",
+    "cache",
+    "services",
+    "resources",
+    "network",
+    "read_only",
+    "cap_drop",
+    "run_as_host_user",
+    "mounts",
+];
 
 #[derive(Debug, PartialEq)]
 pub struct ParserConfig {
     jobs: Vec<JobConfig>,
     stages: Option<Vec<String>>,
     variables: Vec<Variable>,
+    secrets: Vec<SecretFile>,
+    // Directory mounted into every job. --workspace takes precedence
+    workspace: Option<String>,
 }
 
 impl ParserConfig {
@@ -34,18 +81,58 @@ impl ParserConfig {
             jobs,
             stages,
             variables,
+            secrets: vec![],
+            workspace: None,
         }
     }
 
     pub fn parse_from_file(file_path: &str) -> Result<Self, PipelineError> {
-        let config_str = std::fs::read_to_string(file_path)
-            .map_err(|e| ConfigFileNotReadable(file_path.to_string(), e.to_string()))?;
-        Self::parse_str(config_str.as_str())
+        let mut sources = HashMap::new();
+        let config = Self::load_file(Path::new(file_path), &mut vec![], &mut sources)?;
+        Self::parse_mapping(config, &sources)
+    }
+
+    // The config of a job as written, after includes, `extends` and `default`
+    // are applied but before variables are substituted
+    pub fn merged_job_from_file(
+        file_path: &str,
+        job_name: &str,
+    ) -> Result<serde_yml::Mapping, PipelineError> {
+        let config = Self::load_file(Path::new(file_path), &mut vec![], &mut HashMap::new())?;
+        Self::merged_job(&config, job_name)
+    }
+
+    fn merged_job(
+        config: &serde_yml::Mapping,
+        job_name: &str,
+    ) -> Result<serde_yml::Mapping, PipelineError> {
+        let defaults = match config.get("default") {
+            Some(serde_yml::Value::Mapping(defaults)) => defaults.clone(),
+            _ => serde_yml::Mapping::new(),
+        };
+        let job_values: HashMap<String, &serde_yml::Mapping> = config
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str()?.to_string(), value.as_mapping()?)))
+            .collect();
+        // Hidden jobs are only templates
+        if job_name.starts_with('.')
+            || PIPELINE_KEYS.contains(&job_name)
+            || !job_values.contains_key(job_name)
+        {
+            return Err(JobNotFound(job_name.to_string()));
+        }
+
+        let job_value = Self::resolve_extends(job_name, &job_values, &mut vec![])?;
+        Ok(Self::with_defaults(&job_value, &defaults))
     }
 
     pub fn substitute_vars(&self, inp: &str) -> String {
+        Self::substitute_vars_with(inp, &self.variables)
+    }
+
+    fn substitute_vars_with(inp: &str, variables: &[Variable]) -> String {
         let mut substituted_vars = inp.to_string();
-        for var in &self.variables {
+        for var in variables {
             substituted_vars =
                 substituted_vars.replace(format!("${{{}}}", var.key).as_str(), var.value.as_str());
         }
@@ -53,375 +140,3314 @@ impl ParserConfig {
         substituted_vars
     }
 
-    pub fn substitute_job_config(&self, job_config: &JobConfig) -> JobConfig {
+    // `dotenv` holds the variables reported by the jobs this job depends on.
+    // They take precedence over both job and global variables
+    pub fn substitute_job_config(&self, job_config: &JobConfig, dotenv: &[Variable]) -> JobConfig {
         let mut job_config = job_config.clone();
-        job_config.image = self.substitute_vars(job_config.image.as_str());
-        job_config.script = job_config
-            .script
-            .into_iter()
-            .map(|s| self.substitute_vars(s.as_str()))
-            .collect();
+
+        // Job variables may refer to global ones and override them
+        let mut variables: Vec<Variable> = dotenv.to_vec();
+        for var in &job_config.variables {
+            if !variables.iter().any(|v| v.key == var.key) {
+                variables.push(Variable {
+                    key: var.key.clone(),
+                    value: self.substitute_vars(var.value.as_str()),
+                });
+            }
+        }
+        for var in &self.variables {
+            if !variables.iter().any(|v| v.key == var.key) {
+                variables.push(var.clone());
+            }
+        }
+
+        job_config.image.name =
+            Self::substitute_vars_with(job_config.image.name.as_str(), &variables);
+        for script in [
+            &mut job_config.before_script,
+            &mut job_config.script,
+            &mut job_config.after_script,
+        ] {
+            *script = script
+                .iter()
+                .map(|s| Self::substitute_vars_with(s.as_str(), &variables))
+                .collect();
+        }
+        for service in job_config.services.iter_mut() {
+            service.image = Self::substitute_vars_with(service.image.as_str(), &variables);
+            for var in service.variables.iter_mut() {
+                var.value = Self::substitute_vars_with(var.value.as_str(), &variables);
+            }
+        }
+        for cache in job_config.cache.iter_mut() {
+            match cache.key {
+                CacheKey::Literal(ref mut key) => {
+                    *key = Self::substitute_vars_with(key.as_str(), &variables)
+                }
+                CacheKey::Files {
+                    prefix: Some(ref mut prefix),
+                    ..
+                } => *prefix = Self::substitute_vars_with(prefix.as_str(), &variables),
+                CacheKey::Files { prefix: None, .. } => {}
+            }
+            cache.fallback_keys = cache
+                .fallback_keys
+                .iter()
+                .map(|k| Self::substitute_vars_with(k.as_str(), &variables))
+                .collect();
+        }
+        job_config.variables = variables;
 
         job_config
     }
 
-    pub fn parse_str(config_str: &str) -> Result<Self, PipelineError> {
-        let config_yaml = serde_yml::from_str::<serde_yml::Value>(config_str)
-            .map_err(|e| ParsingError(e.to_string()))?;
-        let serde_yml::Value::Mapping(jobs_value) = config_yaml else {
-            return Err(ParsingError("Expected a list of jobs".to_string()));
+    fn with_defaults(
+        job_value: &serde_yml::Mapping,
+        defaults: &serde_yml::Mapping,
+    ) -> serde_yml::Mapping {
+        let mut job_value = job_value.clone();
+        for key in DEFAULT_KEYS {
+            if job_value.get(key).is_none()
+                && let Some(default_value) = defaults.get(key)
+            {
+                job_value.insert(key.into(), default_value.clone());
+            }
+        }
+
+        job_value
+    }
+
+    // Aliases are expanded while parsing but `<<` merge keys have to be applied
+    // before the jobs are interpreted. A single pass only resolves one level, so
+    // merges of anchors that themselves contain merge keys need multiple passes
+    fn apply_merge_keys(value: &mut serde_yml::Value) -> Result<(), PipelineError> {
+        fn has_merge_key(value: &serde_yml::Value) -> bool {
+            match value {
+                serde_yml::Value::Mapping(mapping) => {
+                    mapping.contains_key("<<") || mapping.values().any(has_merge_key)
+                }
+                serde_yml::Value::Sequence(sequence) => sequence.iter().any(has_merge_key),
+                serde_yml::Value::Tagged(tagged) => has_merge_key(&tagged.value),
+                _ => false,
+            }
+        }
+
+        while has_merge_key(value) {
+            value
+                .apply_merge()
+                .map_err(|e| ParsingError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_string_list(
+        value: &serde_yml::Value,
+        key: &str,
+    ) -> Result<Vec<String>, PipelineError> {
+        let serde_yml::Value::Sequence(values) = value else {
+            return Err(ParsingError(format!("{} should be a sequence", key)));
         };
 
-        let mut jobs = Vec::new();
-        let mut stages = None;
-        let mut variables = None;
-        for (name, job_value) in jobs_value.iter() {
-            let serde_yml::Value::String(name) = name else {
-                return Err(ParsingError("name should be a string".to_string()));
+        let mut list = vec![];
+        for elem in values.iter() {
+            let serde_yml::Value::String(elem) = elem else {
+                return Err(ParsingError(format!("{} should only contain strings", key)));
             };
-            if name.as_str() == "stages" {
-                let serde_yml::Value::Sequence(stages_val) = job_value else {
-                    return Err(ParsingError("stages should be a sequence".to_string()));
-                };
+            list.push(elem.to_string());
+        }
 
-                let mut stages_arr = vec![];
-                for stages_elem_val in stages_val.iter() {
-                    let serde_yml::Value::String(elem) = stages_elem_val else {
-                        return Err(ParsingError("stage should be a string".to_string()));
-                    };
-                    stages_arr.push(elem.to_string());
-                }
+        Ok(list)
+    }
 
-                stages = Some(stages_arr);
-                continue;
-            }
+    fn parse_cache(value: &serde_yml::Value) -> Result<CacheConfig, PipelineError> {
+        let serde_yml::Value::Mapping(cache_val) = value else {
+            return Err(ParsingError("cache should be a map".to_string()));
+        };
+        let Some(paths) = cache_val.get("paths") else {
+            return Err(ParsingError("cache should have paths".to_string()));
+        };
 
-            if name.as_str() == "variables" {
-                let serde_yml::Value::Mapping(variables_val) = job_value else {
-                    return Err(ParsingError("variables should be a sequence".to_string()));
+        let mut cache = CacheConfig {
+            paths: Self::parse_string_list(paths, "cache paths")?,
+            ..Default::default()
+        };
+        match cache_val.get("key") {
+            None => {}
+            Some(serde_yml::Value::String(key)) => cache.key = CacheKey::Literal(key.to_string()),
+            Some(serde_yml::Value::Mapping(key)) => {
+                let Some(files) = key.get("files") else {
+                    return Err(ParsingError("cache key should have files".to_string()));
+                };
+                let prefix = match key.get("prefix") {
+                    None => None,
+                    Some(serde_yml::Value::String(prefix)) => Some(prefix.to_string()),
+                    Some(_) => {
+                        return Err(ParsingError(
+                            "cache key prefix should be a string".to_string(),
+                        ));
+                    }
+                };
+                cache.key = CacheKey::Files {
+                    files: Self::parse_string_list(files, "cache key files")?,
+                    prefix,
                 };
+            }
+            Some(_) => {
+                return Err(ParsingError(
+                    "cache key should be a string or a map".to_string(),
+                ));
+            }
+        }
+        if let Some(policy) = cache_val.get("policy") {
+            let Some(policy) = policy.as_str().and_then(CachePolicy::parse) else {
+                return Err(ParsingError(
+                    "cache policy should be one of pull, push or pull-push".to_string(),
+                ));
+            };
+            cache.policy = policy;
+        }
+        if let Some(fallback_keys) = cache_val.get("fallback_keys") {
+            cache.fallback_keys = Self::parse_string_list(fallback_keys, "cache fallback_keys")?;
+        }
 
-                let mut variables_arr = vec![];
-                for (key_val, val_val) in variables_val.iter() {
-                    let key = key_val.as_str().unwrap().to_string();
-                    let value = val_val.as_str().unwrap().to_string();
-                    variables_arr.push(Variable { key, value });
-                }
+        Ok(cache)
+    }
 
-                variables = Some(variables_arr);
-                continue;
+    // Services are either an image name or a map with the image and its options
+    fn parse_service(value: &serde_yml::Value) -> Result<Service, PipelineError> {
+        let service_val = match value {
+            serde_yml::Value::String(image) => {
+                return Ok(Service {
+                    image: image.to_string(),
+                    alias: Service::default_alias(image),
+                    ..Default::default()
+                });
+            }
+            serde_yml::Value::Mapping(service_val) => service_val,
+            _ => {
+                return Err(ParsingError(
+                    "service should be a string or a map".to_string(),
+                ));
             }
+        };
+        let Some(serde_yml::Value::String(image)) = service_val.get("image") else {
+            return Err(ParsingError("service image should be a string".to_string()));
+        };
 
-            let serde_yml::Value::Mapping(job_value) = job_value else {
-                return Err(ParsingError("Each job should be a map".to_string()));
+        let mut service = Service {
+            image: image.to_string(),
+            alias: Service::default_alias(image),
+            ..Default::default()
+        };
+        if let Some(alias) = service_val.get("alias") {
+            let serde_yml::Value::String(alias) = alias else {
+                return Err(ParsingError("service alias should be a string".to_string()));
             };
-
-            let serde_yml::Value::String(image) =
-                job_value.get("image").unwrap_or(&serde_yml::Value::Null)
-            else {
-                return Err(ParsingError("name should be a string".to_string()));
+            if alias.is_empty()
+                || !alias
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+            {
+                return Err(ParsingError(format!(
+                    "service alias {} should be a valid host name",
+                    alias
+                )));
+            }
+            service.alias = alias.to_string();
+        }
+        if let Some(command) = service_val.get("command") {
+            service.command = match command {
+                serde_yml::Value::String(command) => vec![command.to_string()],
+                command => Self::parse_string_list(command, "service command")?,
             };
+        }
+        if let Some(variables) = service_val.get("variables") {
+            service.variables = Self::parse_variables(variables)?;
+        }
 
-            let stage = if let Some(stage) = job_value.get("stage") {
-                let serde_yml::Value::String(stage) = stage else {
-                    return Err(ParsingError("stage should be a string".to_string()));
-                };
+        Ok(service)
+    }
 
-                Some(stage.to_string())
-            } else {
-                None
+    fn parse_resources(value: &serde_yml::Value) -> Result<ResourcesConfig, PipelineError> {
+        let serde_yml::Value::Mapping(resources_val) = value else {
+            return Err(ParsingError("resources should be a map".to_string()));
+        };
+
+        let mut resources = ResourcesConfig::default();
+        if let Some(cpus) = resources_val.get("cpus") {
+            let cpus = match cpus {
+                serde_yml::Value::Number(cpus) => cpus.as_f64(),
+                serde_yml::Value::String(cpus) => cpus.parse().ok(),
+                _ => None,
+            };
+            let Some(cpus) = cpus.filter(|cpus: &f64| *cpus > 0.0) else {
+                return Err(ParsingError(
+                    "resources cpus should be a positive number".to_string(),
+                ));
+            };
+            resources.cpus = Some(cpus);
+        }
+        if let Some(memory) = resources_val.get("memory") {
+            let memory = match memory {
+                serde_yml::Value::Number(memory) => memory.as_u64().filter(|memory| *memory > 0),
+                serde_yml::Value::String(memory) => ResourcesConfig::parse_memory(memory),
+                _ => None,
+            };
+            let Some(memory) = memory else {
+                return Err(ParsingError(
+                    "resources memory should be a size like 512m or 2g".to_string(),
+                ));
             };
+            resources.memory = Some(memory);
+        }
 
-            let needs = if let Some(needs_val) = job_value.get("needs") {
-                let serde_yml::Value::Sequence(needs_arr) = needs_val else {
-                    return Err(ParsingError("needs should be a sequence".to_string()));
-                };
+        Ok(resources)
+    }
 
-                let mut needs = vec![];
-                for needs_elem_val in needs_arr.iter() {
-                    let serde_yml::Value::String(elem) = needs_elem_val else {
-                        return Err(ParsingError("name should be a string".to_string()));
-                    };
-                    needs.push(elem.to_string());
+    // Mounts are either `source:target[:ro|rw]` or a map with the same options
+    fn parse_mount(value: &serde_yml::Value) -> Result<Mount, PipelineError> {
+        let mount_val = match value {
+            serde_yml::Value::String(mount) => {
+                let parts: Vec<&str> = mount.split(':').collect();
+                let read_only = match parts.get(2) {
+                    None | Some(&"ro") => true,
+                    Some(&"rw") => false,
+                    Some(_) => return Err(ParsingError(format!("invalid mount {}", mount))),
+                };
+                if parts.len() < 2 || parts.len() > 3 || parts[0].is_empty() || parts[1].is_empty()
+                {
+                    return Err(ParsingError(format!("invalid mount {}", mount)));
                 }
+                return Ok(Mount {
+                    read_only,
+                    ..Mount::new_with_params(parts[0].to_string(), parts[1].to_string())
+                });
+            }
+            serde_yml::Value::Mapping(mount_val) => mount_val,
+            _ => {
+                return Err(ParsingError(
+                    "mount should be a string or a map".to_string(),
+                ));
+            }
+        };
 
-                Some(needs)
-            } else {
-                None
+        let (Some(serde_yml::Value::String(source)), Some(serde_yml::Value::String(target))) =
+            (mount_val.get("source"), mount_val.get("target"))
+        else {
+            return Err(ParsingError(
+                "mount should have a source and a target".to_string(),
+            ));
+        };
+        let mut mount = Mount::new_with_params(source.to_string(), target.to_string());
+        if let Some(read_only) = mount_val.get("read_only") {
+            let serde_yml::Value::Bool(read_only) = read_only else {
+                return Err(ParsingError(
+                    "mount read_only should be a boolean".to_string(),
+                ));
             };
-            let artifacts = if let Some(artifacts_val) = job_value.get("artifacts") {
-                let serde_yml::Value::Mapping(artifacts_val) = artifacts_val else {
-                    return Err(ParsingError("needs should be a sequence".to_string()));
-                };
+            mount.read_only = *read_only;
+        }
 
-                let Some(artifacts_paths) = artifacts_val.get("paths") else {
-                    return Err(ParsingError("artifacts should have paths".to_string()));
-                };
-                let serde_yml::Value::Sequence(artifacts_paths) = artifacts_paths else {
-                    return Err(ParsingError("needs should be a sequence".to_string()));
-                };
+        Ok(mount)
+    }
 
-                let mut artifacts = vec![];
-                for val in artifacts_paths.iter() {
-                    let serde_yml::Value::String(elem) = val else {
-                        return Err(ParsingError("path should be a string".to_string()));
-                    };
-                    artifacts.push(elem.to_string());
-                }
+    fn parse_variables(value: &serde_yml::Value) -> Result<Vec<Variable>, PipelineError> {
+        let serde_yml::Value::Mapping(variables_val) = value else {
+            return Err(ParsingError("variables should be a map".to_string()));
+        };
 
-                Some(artifacts)
-            } else {
-                None
+        let mut variables = vec![];
+        for (key_val, val_val) in variables_val.iter() {
+            let serde_yml::Value::String(key) = key_val else {
+                return Err(ParsingError("variable name should be a string".to_string()));
             };
-
-            let mut script = vec![];
-            let serde_yml::Value::Sequence(script_val) =
-                job_value.get("script").unwrap_or(&serde_yml::Value::Null)
-            else {
-                return Err(ParsingError("name should be a string".to_string()));
+            let value = match val_val {
+                serde_yml::Value::String(value) => value.to_string(),
+                serde_yml::Value::Number(value) => value.to_string(),
+                serde_yml::Value::Bool(value) => value.to_string(),
+                _ => {
+                    return Err(ParsingError(format!("variable {} should be a string", key)));
+                }
             };
-            for script_elem_val in script_val.iter() {
-                let serde_yml::Value::String(elem) = script_elem_val else {
-                    return Err(ParsingError("name should be a string".to_string()));
-                };
-                script.push(elem.to_string());
-            }
+            variables.push(Variable {
+                key: key.to_string(),
+                value,
+            });
+        }
 
-            let job = JobConfig::new_with_params(
-                name.to_string(),
-                image.to_string(),
-                stage,
-                script,
-                needs,
-                artifacts,
-            );
-            jobs.push(job);
+        Ok(variables)
+    }
+
+    // Deep merges `overlay` into `base`. Nested maps are merged key by key while
+    // any other value in `overlay` replaces the one in `base`
+    fn deep_merge(base: &mut serde_yml::Mapping, overlay: &serde_yml::Mapping) {
+        for (key, value) in overlay.iter() {
+            match (base.get_mut(key), value) {
+                (Some(serde_yml::Value::Mapping(base_value)), serde_yml::Value::Mapping(value)) => {
+                    Self::deep_merge(base_value, value)
+                }
+                _ => {
+                    base.insert(key.clone(), value.clone());
+                }
+            }
         }
-        Ok(Self::new_with_params(
-            jobs,
-            stages,
-            variables.unwrap_or(vec![]),
-        ))
     }
-}
 
-pub struct Pipeline {
-    file_path: String,
-}
+    // Returns the job mapping with everything it `extends` merged in. Parents are
+    // applied in order so later ones take precedence, and the job's own keys win
+    // over all of them
+    fn resolve_extends(
+        name: &str,
+        job_values: &HashMap<String, &serde_yml::Mapping>,
+        stack: &mut Vec<String>,
+    ) -> Result<serde_yml::Mapping, PipelineError> {
+        if stack.iter().any(|n| n == name) {
+            stack.push(name.to_string());
+            return Err(ParsingError(format!(
+                "extends cycle detected: {}",
+                stack.join(" -> ")
+            )));
+        }
+        let Some(job_value) = job_values.get(name) else {
+            return Err(ParsingError(format!(
+                "{} extends unknown job {}",
+                stack.last().map(|s| s.as_str()).unwrap_or_default(),
+                name
+            )));
+        };
 
-impl Pipeline {
-    pub fn new_with_params(file_path: String) -> Self {
-        Self { file_path }
-    }
-    fn get_jobs_by_stage(jobs: Vec<JobConfig>) -> HashMap<Option<String>, Vec<JobConfig>> {
-        let mut jobs_by_stage = HashMap::new();
+        let parents = match job_value.get("extends") {
+            None => vec![],
+            Some(serde_yml::Value::String(parent)) => vec![parent.to_string()],
+            Some(parents) => Self::parse_string_list(parents, "extends")?,
+        };
 
-        for job in jobs {
-            let stage = job.stage.clone();
-            let entry = jobs_by_stage.entry(stage);
-            entry
-                .and_modify(|v: &mut Vec<JobConfig>| v.push(job.clone()))
-                .or_insert(vec![job.clone()]);
+        stack.push(name.to_string());
+        let mut merged = serde_yml::Mapping::new();
+        for parent in parents {
+            let parent_value = Self::resolve_extends(parent.as_str(), job_values, stack)?;
+            Self::deep_merge(&mut merged, &parent_value);
         }
+        stack.pop();
 
-        jobs_by_stage
+        Self::deep_merge(&mut merged, job_value);
+        merged.remove("extends");
+
+        Ok(merged)
     }
 
-    fn get_execution_order(jobs: Vec<&JobConfig>) -> Vec<Vec<&JobConfig>> {
-        let mut execution_order = vec![];
-        let mut jobs_by_name = HashMap::new();
-        let mut graph = HashMap::new();
-        let mut job_names = vec![];
-        for job in jobs {
-            let job_name = job.name.clone();
-            job_names.push(job_name.clone());
-            jobs_by_name.insert(job_name.clone(), job);
+    fn parse_scalar(value: &serde_yml::Value) -> Option<String> {
+        match value {
+            serde_yml::Value::String(value) => Some(value.to_string()),
+            serde_yml::Value::Number(value) => Some(value.to_string()),
+            serde_yml::Value::Bool(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
 
-            if let Some(ref needs) = job.needs {
-                for deps in needs {
-                    let entry = graph.entry(job_name.clone());
-                    entry
-                        .and_modify(|d: &mut Vec<String>| d.push(deps.clone()))
-                        .or_insert(vec![deps.clone()]);
-                }
+    // Every entry of a matrix expands into the cartesian product of its variables.
+    // A variable is either a single value or a list of values
+    fn parse_matrix(matrix: &serde_yml::Value) -> Result<Vec<Vec<Variable>>, PipelineError> {
+        let serde_yml::Value::Sequence(entries) = matrix else {
+            return Err(ParsingError("matrix should be a sequence".to_string()));
+        };
+
+        let mut cells = vec![];
+        for entry in entries.iter() {
+            let serde_yml::Value::Mapping(entry) = entry else {
+                return Err(ParsingError("matrix entries should be maps".to_string()));
+            };
+
+            let mut entry_cells: Vec<Vec<Variable>> = vec![vec![]];
+            for (key, values) in entry.iter() {
+                let serde_yml::Value::String(key) = key else {
+                    return Err(ParsingError(
+                        "matrix variable should be a string".to_string(),
+                    ));
+                };
+                let values = match values {
+                    serde_yml::Value::Sequence(values) => {
+                        values.iter().map(Self::parse_scalar).collect()
+                    }
+                    value => Self::parse_scalar(value).map(|value| vec![value]),
+                };
+                let Some(values) = values.filter(|values| !values.is_empty()) else {
+                    return Err(ParsingError(format!(
+                        "matrix variable {} should be a value or a list of values",
+                        key
+                    )));
+                };
+
+                entry_cells = entry_cells
+                    .iter()
+                    .flat_map(|cell| {
+                        values.iter().map(move |value| {
+                            let mut cell = cell.clone();
+                            cell.push(Variable {
+                                key: key.to_string(),
+                                value: value.to_string(),
+                            });
+                            cell
+                        })
+                    })
+                    .collect();
             }
+            cells.extend(entry_cells);
         }
 
-        while jobs_by_name.len() != 0 {
-            let mut runnable_jobs = vec![];
+        Ok(cells)
+    }
 
-            for curr_job_name in jobs_by_name.keys() {
-                let deps = graph.get(curr_job_name);
+    // `parallel: N` runs N copies of the job named `job 1/N` to `job N/N`. Each
+    // one gets its index and the total in CI_NODE_INDEX and CI_NODE_TOTAL.
+    // `parallel: matrix:` runs one job per matrix cell named after the values of
+    // its variables, like `job: [3.11, postgres]`
+    fn expand_parallel(
+        job: JobConfig,
+        parallel: Option<&serde_yml::Value>,
+    ) -> Result<Vec<JobConfig>, PipelineError> {
+        let Some(parallel) = parallel else {
+            return Ok(vec![job]);
+        };
 
-                // This job has no dependencies, so we can run it now
-                if deps.is_none() {
-                    runnable_jobs.push(curr_job_name.clone());
-                } else if let Some(deps) = deps {
-                    if deps.len() == 0 {
-                        runnable_jobs.push(curr_job_name.clone());
-                    }
-                }
+        if let serde_yml::Value::Mapping(parallel) = parallel {
+            let Some(matrix) = parallel.get("matrix") else {
+                return Err(ParsingError("parallel should have a matrix".to_string()));
+            };
+            let cells = Self::parse_matrix(matrix)?;
+            if cells.is_empty() || cells.len() as u64 > MAX_PARALLEL_JOBS {
+                return Err(ParsingError(format!(
+                    "matrix should expand into 1 to {} jobs",
+                    MAX_PARALLEL_JOBS
+                )));
             }
 
-            let mut parallel_jobs = vec![];
-            for runnable_job in runnable_jobs.iter() {
-                let job = jobs_by_name.remove(runnable_job).expect("should exist");
-                parallel_jobs.push(job);
+            let mut jobs = vec![];
+            for cell in cells {
+                let mut matrix_job = job.clone();
+                let values: Vec<&str> = cell.iter().map(|v| v.value.as_str()).collect();
+                matrix_job.name = format!("{}: [{}]", job.name, values.join(", "));
+                for var in cell {
+                    matrix_job.variables.retain(|v| v.key != var.key);
+                    matrix_job.variables.push(var);
+                }
+                jobs.push(matrix_job);
             }
 
-            parallel_jobs.sort_by(|a, b| a.name.cmp(&b.name));
-            execution_order.push(parallel_jobs);
+            return Ok(jobs);
+        }
 
-            for curr_job_name in job_names.iter() {
-                let deps = graph.get_mut(curr_job_name);
+        let Some(total) = parallel
+            .as_u64()
+            .filter(|total| (1..=MAX_PARALLEL_JOBS).contains(total))
+        else {
+            return Err(ParsingError(format!(
+                "parallel should be a number between 1 and {}",
+                MAX_PARALLEL_JOBS
+            )));
+        };
 
-                // Remove the runnable jobs from deps of other jobs
-                if let Some(deps) = deps {
-                    for runnable_job in runnable_jobs.iter() {
-                        let idx = deps.iter().position(|d| d == runnable_job);
-                        if let Some(idx) = idx {
-                            deps.remove(idx);
-                        }
-                    }
-                }
+        let mut jobs = vec![];
+        for index in 1..=total {
+            let mut parallel_job = job.clone();
+            parallel_job.name = format!("{} {}/{}", job.name, index, total);
+            for (key, value) in [("CI_NODE_INDEX", index), ("CI_NODE_TOTAL", total)] {
+                parallel_job.variables.retain(|v| v.key != key);
+                parallel_job.variables.push(Variable {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                });
             }
+            jobs.push(parallel_job);
         }
 
-        execution_order
+        Ok(jobs)
     }
 
-    async fn execute_job(job: JobConfig, artifact_manager: ArtifactManager) {
-        let job_name = job.name.clone();
-        if let Err(e) = tokio::task::spawn_blocking(|| {
-            let executor = Executor::new_with_params(None);
-            let job = job;
-            let artifact_manager = artifact_manager;
-            if let Err(err) = executor.run(&job, &artifact_manager) {
-                println!("{} job failed| {}", job.name, err);
-            }
-        })
-        .await
-        {
-            println!("{} job failed| {}", job_name, e);
+    // The matrix cells selected by each `needs` entry, in the order of the
+    // entries. Entries that don't select cells are `None`
+    fn parse_matrix_needs(
+        needs: Option<&serde_yml::Value>,
+    ) -> Result<Vec<Option<Vec<Vec<Variable>>>>, PipelineError> {
+        let Some(serde_yml::Value::Sequence(needs)) = needs else {
+            return Ok(vec![]);
+        };
+
+        let mut matrix_needs = vec![];
+        for need in needs.iter() {
+            let Some(parallel) = need.get("parallel") else {
+                matrix_needs.push(None);
+                continue;
+            };
+            let Some(matrix) = parallel.get("matrix") else {
+                return Err(ParsingError(
+                    "needs parallel should have a matrix".to_string(),
+                ));
+            };
+            matrix_needs.push(Some(Self::parse_matrix(matrix)?));
         }
+
+        Ok(matrix_needs)
     }
 
-    async fn run_internal(config: ParserConfig) {
-        let artifact_manager = ArtifactManager::new_with_params(
-            DEFAULT_WORKSPACE.to_string(),
-            DEFAULT_ARTIFACT_LOCATION.to_string(),
-        );
-        let jobs: Vec<JobConfig> = config
-            .jobs
-            .iter()
-            .map(|j| config.substitute_job_config(j))
-            .collect();
-        let jobs_by_stage = Self::get_jobs_by_stage(config.jobs);
-        if let Some(_stages) = config.stages {
-            let execution_order = Self::get_execution_order(jobs.iter().collect());
-            for parallel_jobs in execution_order {
-                let mut jobs_set = tokio::task::JoinSet::new();
-                for job in parallel_jobs {
-                    jobs_set.spawn(Self::execute_job(job.clone(), artifact_manager.clone()));
-                }
-                jobs_set.join_all().await;
+    // Images are either a name or a map with the name and how to run it
+    fn parse_image(value: &serde_yml::Value) -> Result<ImageConfig, PipelineError> {
+        let image_val = match value {
+            serde_yml::Value::String(name) => {
+                return Ok(ImageConfig::new_with_params(name.to_string()));
             }
-        } else {
-            if let Some(jobs) = jobs_by_stage.get(&None) {
-                println!("Executing without a stage");
-                let mut jobs_set = tokio::task::JoinSet::new();
+            serde_yml::Value::Mapping(image_val) => image_val,
+            _ => return Err(ParsingError("name should be a string".to_string())),
+        };
+        let Some(serde_yml::Value::String(name)) = image_val.get("name") else {
+            return Err(ParsingError("image name should be a string".to_string()));
+        };
 
-                for job in jobs {
-                    jobs_set.spawn(Self::execute_job(job.clone(), artifact_manager.clone()));
-                }
-                jobs_set.join_all().await;
+        let mut image = ImageConfig::new_with_params(name.to_string());
+        if let Some(entrypoint) = image_val.get("entrypoint") {
+            image.entrypoint = Some(Self::parse_string_list(entrypoint, "image entrypoint")?);
+        }
+        if let Some(pull_policy) = image_val.get("pull_policy") {
+            let Some(pull_policy) = pull_policy.as_str().and_then(PullPolicy::parse) else {
+                return Err(ParsingError(
+                    "image pull_policy should be one of always, if-not-present or never"
+                        .to_string(),
+                ));
             };
+            image.pull_policy = pull_policy;
         }
-
-        if let Err(e) = artifact_manager.cleanup() {
-            println!("Artifact cleanup failed: {:?}", e.to_string());
+        if let Some(docker) = image_val.get("docker") {
+            let serde_yml::Value::Mapping(docker) = docker else {
+                return Err(ParsingError("image docker should be a map".to_string()));
+            };
+            for (key, option) in [("platform", &mut image.platform), ("user", &mut image.user)] {
+                if let Some(value) = docker.get(key) {
+                    let serde_yml::Value::String(value) = value else {
+                        return Err(ParsingError(format!(
+                            "image docker {} should be a string",
+                            key
+                        )));
+                    };
+                    *option = Some(value.to_string());
+                }
+            }
         }
-    }
 
-    pub fn run(&self) -> Result<(), PipelineError> {
-        let rt = Runtime::new().map_err(|e| RuntimeError(e.to_string()))?;
-        let config = ParserConfig::parse_from_file(self.file_path.as_str())?;
-        rt.block_on(async { Self::run_internal(config).await });
-        Ok(())
+        Ok(image)
     }
-}
 
-#[cfg(test)]
-mod tests {
+    fn parse_job(name: &str, job_value: &serde_yml::Mapping) -> Result<JobConfig, PipelineError> {
+        let image = Self::parse_image(job_value.get("image").unwrap_or(&serde_yml::Value::Null))?;
 
-    use super::*;
+        let stage = if let Some(stage) = job_value.get("stage") {
+            let serde_yml::Value::String(stage) = stage else {
+                return Err(ParsingError("stage should be a string".to_string()));
+            };
 
-    #[test]
-    fn test_parse_str() {
-        let config = r#"
-build-job:
-  image: python:3.11
-  artifacts:
-    paths:
-      - dist
-  needs:
-    - unit-tests
-    - integration-tests
-  script:
-    - echo "Building application..."
+            Some(stage.to_string())
+        } else {
+            None
+        };
+
+        let needs = if let Some(needs_val) = job_value.get("needs") {
+            let serde_yml::Value::Sequence(needs_arr) = needs_val else {
+                return Err(ParsingError("needs should be a sequence".to_string()));
+            };
+
+            let mut needs = vec![];
+            for needs_elem_val in needs_arr.iter() {
+                // The object form is `{ job: name, artifacts: false, optional: true,
+                // parallel: { matrix: ... } }`
+                let need = match needs_elem_val {
+                    serde_yml::Value::String(elem) => {
+                        Need::new_with_params(elem.to_string(), true, false)
+                    }
+                    serde_yml::Value::Mapping(need_val) => {
+                        let Some(serde_yml::Value::String(elem)) = need_val.get("job") else {
+                            return Err(ParsingError("needs should have a job".to_string()));
+                        };
+                        let mut need = Need::new_with_params(elem.to_string(), true, false);
+                        if let Some(artifacts) = need_val.get("artifacts") {
+                            let serde_yml::Value::Bool(artifacts) = artifacts else {
+                                return Err(ParsingError(
+                                    "needs artifacts should be a boolean".to_string(),
+                                ));
+                            };
+                            need.artifacts = *artifacts;
+                        }
+                        if let Some(optional) = need_val.get("optional") {
+                            let serde_yml::Value::Bool(optional) = optional else {
+                                return Err(ParsingError(
+                                    "needs optional should be a boolean".to_string(),
+                                ));
+                            };
+                            need.optional = *optional;
+                        }
+                        need
+                    }
+                    _ => return Err(ParsingError("name should be a string".to_string())),
+                };
+                needs.push(need);
+            }
+
+            Some(needs)
+        } else {
+            None
+        };
+        let artifacts = if let Some(artifacts_val) = job_value.get("artifacts") {
+            let serde_yml::Value::Mapping(artifacts_val) = artifacts_val else {
+                return Err(ParsingError("artifacts should be a map".to_string()));
+            };
+
+            // Jobs that only publish reports don't need paths
+            let mut artifacts = ArtifactsConfig::default();
+            match (artifacts_val.get("paths"), artifacts_val.get("reports")) {
+                (Some(artifacts_paths), _) => {
+                    artifacts.paths = Self::parse_string_list(artifacts_paths, "artifacts paths")?
+                }
+                (None, Some(_)) => {}
+                (None, None) => {
+                    return Err(ParsingError("artifacts should have paths".to_string()));
+                }
+            }
+            if let Some(reports) = artifacts_val.get("reports") {
+                let serde_yml::Value::Mapping(reports) = reports else {
+                    return Err(ParsingError(
+                        "artifacts reports should be a map".to_string(),
+                    ));
+                };
+                // A single report can be given as a string
+                if let Some(junit) = reports.get("junit") {
+                    artifacts.reports.junit = match junit {
+                        serde_yml::Value::String(junit) => vec![junit.to_string()],
+                        junit => Self::parse_string_list(junit, "artifacts reports junit")?,
+                    };
+                }
+                if let Some(dotenv) = reports.get("dotenv") {
+                    let serde_yml::Value::String(dotenv) = dotenv else {
+                        return Err(ParsingError(
+                            "artifacts reports dotenv should be a string".to_string(),
+                        ));
+                    };
+                    artifacts.reports.dotenv = Some(dotenv.to_string());
+                }
+            }
+            if let Some(exclude) = artifacts_val.get("exclude") {
+                artifacts.exclude = Self::parse_string_list(exclude, "artifacts exclude")?;
+            }
+            if let Some(format) = artifacts_val.get("format") {
+                let Some(format) = format.as_str().and_then(ArtifactFormat::parse) else {
+                    return Err(ParsingError(
+                        "artifacts format should be one of files, tar, tar.gz or tar.zst"
+                            .to_string(),
+                    ));
+                };
+                artifacts.format = format;
+            }
+            if let Some(when) = artifacts_val.get("when") {
+                let Some(when) = when.as_str().and_then(ArtifactsWhen::parse) else {
+                    return Err(ParsingError(
+                        "artifacts when should be one of on_success, on_failure or always"
+                            .to_string(),
+                    ));
+                };
+                artifacts.when = when;
+            }
+            if let Some(expire_in) = artifacts_val.get("expire_in")
+                && expire_in.as_str() != Some("never")
+            {
+                let expire_in = match expire_in {
+                    serde_yml::Value::Number(secs) => secs.as_u64().map(Duration::from_secs),
+                    serde_yml::Value::String(expire_in) => parse_duration(expire_in).ok(),
+                    _ => None,
+                };
+                let Some(expire_in) = expire_in else {
+                    return Err(ParsingError(
+                        "artifacts expire_in should be a duration".to_string(),
+                    ));
+                };
+                artifacts.expire_in = Some(expire_in);
+            }
+
+            Some(artifacts)
+        } else {
+            None
+        };
+
+        let mut script = vec![];
+        let serde_yml::Value::Sequence(script_val) =
+            job_value.get("script").unwrap_or(&serde_yml::Value::Null)
+        else {
+            return Err(ParsingError("name should be a string".to_string()));
+        };
+        for script_elem_val in script_val.iter() {
+            let serde_yml::Value::String(elem) = script_elem_val else {
+                return Err(ParsingError("name should be a string".to_string()));
+            };
+            script.push(elem.to_string());
+        }
+
+        let mut job =
+            JobConfig::new_with_params(name.to_string(), image, stage, script, needs, artifacts);
+
+        // A job can use multiple caches, e.g. one for pip and one for npm
+        match job_value.get("cache") {
+            None => {}
+            Some(serde_yml::Value::Sequence(caches)) => {
+                for cache in caches.iter() {
+                    job.cache.push(Self::parse_cache(cache)?);
+                }
+            }
+            Some(cache) => job.cache.push(Self::parse_cache(cache)?),
+        }
+        if let Some(services) = job_value.get("services") {
+            let serde_yml::Value::Sequence(services) = services else {
+                return Err(ParsingError("services should be a list".to_string()));
+            };
+            for service in services.iter() {
+                let service = Self::parse_service(service)?;
+                if job.services.iter().any(|s| s.alias == service.alias) {
+                    return Err(ParsingError(format!(
+                        "{} has more than one service with alias {}",
+                        name, service.alias
+                    )));
+                }
+                job.services.push(service);
+            }
+        }
+        if let Some(resources) = job_value.get("resources") {
+            job.resources = Self::parse_resources(resources)?;
+        }
+        if let Some(network) = job_value.get("network") {
+            let Some(network) = network.as_str().and_then(NetworkMode::parse) else {
+                return Err(ParsingError(
+                    "network should be one of none or bridge".to_string(),
+                ));
+            };
+            job.network = network;
+        }
+        if job.network == NetworkMode::None && !job.services.is_empty() {
+            return Err(ParsingError(format!(
+                "{} can't use services without a network",
+                name
+            )));
+        }
+        for (key, option) in [
+            ("read_only", &mut job.read_only),
+            ("run_as_host_user", &mut job.run_as_host_user),
+        ] {
+            if let Some(value) = job_value.get(key) {
+                let serde_yml::Value::Bool(value) = value else {
+                    return Err(ParsingError(format!("{} should be a boolean", key)));
+                };
+                *option = *value;
+            }
+        }
+        if job.run_as_host_user && job.image.user.is_some() {
+            return Err(ParsingError(format!(
+                "{} can't set both run_as_host_user and an image user",
+                name
+            )));
+        }
+        if let Some(when) = job_value.get("when") {
+            let Some(when) = when.as_str().and_then(JobWhen::parse) else {
+                return Err(ParsingError(
+                    "when should be one of on_success or always".to_string(),
+                ));
+            };
+            job.when = when;
+        }
+        if let Some(mounts) = job_value.get("mounts") {
+            let serde_yml::Value::Sequence(mounts) = mounts else {
+                return Err(ParsingError("mounts should be a list".to_string()));
+            };
+            for mount in mounts.iter() {
+                job.mounts.push(Self::parse_mount(mount)?);
+            }
+        }
+        if let Some(cap_drop) = job_value.get("cap_drop") {
+            job.cap_drop = Self::parse_string_list(cap_drop, "cap_drop")?;
+        }
+        if let Some(cache_result) = job_value.get("cache_result") {
+            let serde_yml::Value::Bool(cache_result) = cache_result else {
+                return Err(ParsingError("cache_result should be a boolean".to_string()));
+            };
+            job.cache_result = *cache_result;
+        }
+        if let Some(inputs) = job_value.get("inputs") {
+            job.inputs = Self::parse_string_list(inputs, "inputs")?;
+        }
+        // Without inputs the result would be reused whatever the workspace holds
+        if job.cache_result && job.inputs.is_empty() {
+            return Err(ParsingError("cache_result requires inputs".to_string()));
+        }
+        if let Some(coverage) = job_value.get("coverage") {
+            let serde_yml::Value::String(coverage) = coverage else {
+                return Err(ParsingError("coverage should be a string".to_string()));
+            };
+            if let Err(e) = coverage_regex(coverage) {
+                return Err(ParsingError(format!(
+                    "coverage should be a valid regex: {}",
+                    e
+                )));
+            }
+            job.coverage = Some(coverage.to_string());
+        }
+        if let Some(dependencies) = job_value.get("dependencies") {
+            job.dependencies = Some(Self::parse_string_list(dependencies, "dependencies")?);
+        }
+        if let Some(before_script) = job_value.get("before_script") {
+            job.before_script = Self::parse_string_list(before_script, "before_script")?;
+        }
+        if let Some(after_script) = job_value.get("after_script") {
+            job.after_script = Self::parse_string_list(after_script, "after_script")?;
+        }
+
+        if let Some(timeout) = job_value.get("timeout") {
+            let timeout = match timeout {
+                serde_yml::Value::Number(secs) => secs.as_u64().map(Duration::from_secs),
+                serde_yml::Value::String(timeout) => parse_duration(timeout).ok(),
+                _ => None,
+            };
+            let Some(timeout) = timeout else {
+                return Err(ParsingError("timeout should be a duration".to_string()));
+            };
+            job.timeout = Some(timeout);
+        }
+
+        if let Some(retry) = job_value.get("retry") {
+            // Both `retry: 2` and `retry: { max: 2 }` are supported
+            let retry = match retry {
+                serde_yml::Value::Mapping(retry) => retry.get("max").and_then(|m| m.as_u64()),
+                retry => retry.as_u64(),
+            };
+            let Some(retry) = retry else {
+                return Err(ParsingError("retry should be a number".to_string()));
+            };
+            job.retry = u32::try_from(retry)
+                .map_err(|_| ParsingError(format!("retry should be at most {}", u32::MAX)))?;
+        }
+
+        if let Some(variables) = job_value.get("variables") {
+            job.variables = Self::parse_variables(variables)?;
+        }
+
+        Ok(job)
+    }
+
+    fn load_yaml(config_str: &str) -> Result<serde_yml::Mapping, PipelineError> {
+        let mut config_yaml = serde_yml::from_str::<serde_yml::Value>(config_str)
+            .map_err(|e| ParsingError(e.to_string()))?;
+        Self::apply_merge_keys(&mut config_yaml)?;
+        let serde_yml::Value::Mapping(jobs_value) = config_yaml else {
+            return Err(ParsingError("Expected a list of jobs".to_string()));
+        };
+
+        Ok(jobs_value)
+    }
+
+    // Prefixes parsing errors with the file they originate from
+    fn in_file(e: PipelineError, file_path: &str) -> PipelineError {
+        match e {
+            ParsingError(msg) if !file_path.is_empty() => {
+                ParsingError(format!("{}: {}", file_path, msg))
+            }
+            e => e,
+        }
+    }
+
+    // Relative paths in the config are relative to the file defining `key`, not
+    // to wherever the runner was started from
+    fn in_source_dir(path: &str, key: &str, sources: &HashMap<String, String>) -> String {
+        let Some(dir) = sources.get(key).and_then(|file| Path::new(file).parent()) else {
+            return path.to_string();
+        };
+        dir.join(path).display().to_string()
+    }
+
+    // Loads a config file along with everything it includes. `stack` holds the
+    // files currently being loaded and `sources` records the file each top level
+    // key was last defined in, so errors can point at the right file
+    fn load_file(
+        file_path: &Path,
+        stack: &mut Vec<PathBuf>,
+        sources: &mut HashMap<String, String>,
+    ) -> Result<serde_yml::Mapping, PipelineError> {
+        let display_path = file_path.display().to_string();
+        let canonical_path = std::fs::canonicalize(file_path)
+            .map_err(|e| ConfigFileNotReadable(display_path.clone(), e.to_string()))?;
+
+        if stack.contains(&canonical_path) {
+            let mut cycle: Vec<String> = stack.iter().map(|p| p.display().to_string()).collect();
+            cycle.push(canonical_path.display().to_string());
+            return Err(ParsingError(format!(
+                "include cycle detected: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        if stack.len() >= MAX_INCLUDE_DEPTH {
+            return Err(ParsingError(format!(
+                "{}: includes are nested more than {} levels deep",
+                display_path, MAX_INCLUDE_DEPTH
+            )));
+        }
+
+        let config_str = std::fs::read_to_string(file_path)
+            .map_err(|e| ConfigFileNotReadable(display_path.clone(), e.to_string()))?;
+        let config = Self::load_yaml(config_str.as_str())
+            .map_err(|e| Self::in_file(e, display_path.as_str()))?;
+
+        let base_dir = file_path.parent().unwrap_or(Path::new("."));
+        stack.push(canonical_path);
+        let config =
+            Self::resolve_includes(config, base_dir, display_path.as_str(), stack, sources);
+        stack.pop();
+
+        config
+    }
+
+    fn parse_includes(include: &serde_yml::Value) -> Result<Vec<String>, PipelineError> {
+        match include {
+            serde_yml::Value::String(path) => Ok(vec![path.to_string()]),
+            serde_yml::Value::Mapping(include) => match include.get("local") {
+                Some(serde_yml::Value::String(path)) => Ok(vec![path.to_string()]),
+                _ => Err(ParsingError("include should have a local path".to_string())),
+            },
+            serde_yml::Value::Sequence(includes) => {
+                let mut paths = vec![];
+                for include in includes {
+                    paths.extend(Self::parse_includes(include)?);
+                }
+                Ok(paths)
+            }
+            _ => Err(ParsingError(
+                "include should be a path or a list of paths".to_string(),
+            )),
+        }
+    }
+
+    // Included files are deep merged in the order they are listed, with glob
+    // matches sorted by path. The including file is merged last so its keys take
+    // precedence over everything it includes
+    fn resolve_includes(
+        mut config: serde_yml::Mapping,
+        base_dir: &Path,
+        file_path: &str,
+        stack: &mut Vec<PathBuf>,
+        sources: &mut HashMap<String, String>,
+    ) -> Result<serde_yml::Mapping, PipelineError> {
+        let mut merged = serde_yml::Mapping::new();
+        if let Some(include) = config.remove("include") {
+            let patterns =
+                Self::parse_includes(&include).map_err(|e| Self::in_file(e, file_path))?;
+
+            for pattern in patterns {
+                let include_path = Path::new(&Pattern::escape(base_dir.to_string_lossy().as_ref()))
+                    .join(pattern.as_str());
+                let mut include_paths: Vec<PathBuf> = glob(include_path.to_string_lossy().as_ref())
+                    .map_err(|e| Self::in_file(ParsingError(e.to_string()), file_path))?
+                    .flatten()
+                    .collect();
+                include_paths.sort();
+
+                if include_paths.is_empty() {
+                    return Err(ParsingError(format!(
+                        "{}: include {} did not match any files",
+                        file_path, pattern
+                    )));
+                }
+
+                for include_path in include_paths {
+                    let included = Self::load_file(include_path.as_path(), stack, sources)?;
+                    Self::deep_merge(&mut merged, &included);
+                }
+            }
+        }
+
+        for key in config.keys() {
+            if let serde_yml::Value::String(key) = key {
+                sources.insert(key.to_string(), file_path.to_string());
+            }
+        }
+        Self::deep_merge(&mut merged, &config);
+
+        Ok(merged)
+    }
+
+    // Includes are resolved relative to the current directory. Only the tests parse
+    // configs that don't come from a file
+    #[cfg(test)]
+    pub fn parse_str(config_str: &str) -> Result<Self, PipelineError> {
+        let config = Self::load_yaml(config_str)?;
+        let mut sources = HashMap::new();
+        let config = Self::resolve_includes(config, Path::new("."), "", &mut vec![], &mut sources)?;
+        Self::parse_mapping(config, &sources)
+    }
+
+    fn parse_mapping(
+        jobs_value: serde_yml::Mapping,
+        sources: &HashMap<String, String>,
+    ) -> Result<Self, PipelineError> {
+        let mut jobs = Vec::new();
+        let mut stages = None;
+        let mut variables = None;
+        let mut secrets = vec![];
+        let mut workspace = None;
+        let mut job_names = vec![];
+        let mut job_values = HashMap::new();
+
+        let defaults = match jobs_value.get("default") {
+            Some(serde_yml::Value::Mapping(defaults)) => defaults.clone(),
+            Some(_) => return Err(ParsingError("default should be a map".to_string())),
+            None => serde_yml::Mapping::new(),
+        };
+
+        for (name, job_value) in jobs_value.iter() {
+            let serde_yml::Value::String(name) = name else {
+                return Err(ParsingError("name should be a string".to_string()));
+            };
+            if name.as_str() == "stages" {
+                let serde_yml::Value::Sequence(stages_val) = job_value else {
+                    return Err(ParsingError("stages should be a sequence".to_string()));
+                };
+
+                let mut stages_arr = vec![];
+                for stages_elem_val in stages_val.iter() {
+                    let serde_yml::Value::String(elem) = stages_elem_val else {
+                        return Err(ParsingError("stage should be a string".to_string()));
+                    };
+                    stages_arr.push(elem.to_string());
+                }
+
+                stages = Some(stages_arr);
+                continue;
+            }
+
+            if name.as_str() == "variables" {
+                variables = Some(Self::parse_variables(job_value)?);
+                continue;
+            }
+
+            // Secrets are read from local files at run time. They are only exposed to
+            // jobs as environment variables and are never substituted in the config
+            if name.as_str() == "secrets" {
+                let serde_yml::Value::Mapping(secrets_val) = job_value else {
+                    return Err(ParsingError("secrets should be a map".to_string()));
+                };
+
+                for (key_val, path_val) in secrets_val.iter() {
+                    let serde_yml::Value::String(key) = key_val else {
+                        return Err(ParsingError("secret name should be a string".to_string()));
+                    };
+                    let serde_yml::Value::String(path) = path_val else {
+                        return Err(ParsingError("secret file should be a string".to_string()));
+                    };
+                    secrets.push(SecretFile::new_with_params(
+                        key.to_string(),
+                        Self::in_source_dir(path, "secrets", sources),
+                    ));
+                }
+                continue;
+            }
+
+            if name.as_str() == "workspace" {
+                let serde_yml::Value::String(path) = job_value else {
+                    return Err(ParsingError("workspace should be a string".to_string()));
+                };
+                workspace = Some(Self::in_source_dir(path, "workspace", sources));
+                continue;
+            }
+
+            if name.as_str() == "default" {
+                continue;
+            }
+
+            let serde_yml::Value::Mapping(job_value) = job_value else {
+                return Err(ParsingError("Each job should be a map".to_string()));
+            };
+            job_values.insert(name.to_string(), job_value);
+
+            // Hidden jobs are only used as templates for `extends`
+            if !name.starts_with('.') {
+                job_names.push(name.to_string());
+            }
+        }
+
+        // Parallel jobs are expanded here, so `parallel_jobs` maps the original
+        // job name to the names of the jobs it expanded into
+        let mut parallel_jobs: HashMap<String, Vec<String>> = HashMap::new();
+        let mut matrix_needs = HashMap::new();
+        for name in job_names {
+            let (expanded_jobs, job_matrix_needs) =
+                Self::resolve_extends(name.as_str(), &job_values, &mut vec![])
+                    .and_then(|job_value| {
+                        let job_value = Self::with_defaults(&job_value, &defaults);
+                        let job = Self::parse_job(name.as_str(), &job_value)?;
+                        Ok((
+                            Self::expand_parallel(job, job_value.get("parallel"))?,
+                            Self::parse_matrix_needs(job_value.get("needs"))?,
+                        ))
+                    })
+                    .map_err(|e| match sources.get(&name) {
+                        Some(file_path) => Self::in_file(e, file_path),
+                        None => e,
+                    })?;
+
+            for job in expanded_jobs.iter() {
+                matrix_needs.insert(job.name.clone(), job_matrix_needs.clone());
+            }
+
+            if expanded_jobs.len() != 1 || expanded_jobs[0].name != name {
+                parallel_jobs.insert(
+                    name.clone(),
+                    expanded_jobs.iter().map(|j| j.name.clone()).collect(),
+                );
+            }
+            jobs.extend(expanded_jobs);
+        }
+
+        // Needing a parallel job means needing every job it expanded into, unless
+        // specific matrix cells were selected. The same goes for dependencies
+        let job_variables: HashMap<String, Vec<Variable>> = jobs
+            .iter()
+            .map(|j| (j.name.clone(), j.variables.clone()))
+            .collect();
+        for job in jobs.iter_mut() {
+            if let Some(ref mut dependencies) = job.dependencies {
+                *dependencies = dependencies
+                    .iter()
+                    .flat_map(|dependency| {
+                        parallel_jobs
+                            .get(dependency)
+                            .cloned()
+                            .unwrap_or(vec![dependency.clone()])
+                    })
+                    .collect();
+            }
+
+            let Some(ref mut needs) = job.needs else {
+                continue;
+            };
+
+            // Entries selecting overlapping cells of the same job may expand to
+            // the same job twice, which is only needed once
+            let mut expanded = HashSet::new();
+            let mut resolved_needs = vec![];
+            for (i, need) in needs.iter().enumerate() {
+                let Some(expanded_names) = parallel_jobs.get(&need.job) else {
+                    resolved_needs.push(need.clone());
+                    continue;
+                };
+                let expanded_need = |name: &String| {
+                    expanded.insert(name.clone()).then(|| Need {
+                        job: name.clone(),
+                        ..need.clone()
+                    })
+                };
+                let Some(Some(cells)) = matrix_needs[&job.name].get(i) else {
+                    resolved_needs.extend(expanded_names.iter().filter_map(expanded_need));
+                    continue;
+                };
+
+                let selected: Vec<&String> = expanded_names
+                    .iter()
+                    .filter(|name| {
+                        cells
+                            .iter()
+                            .any(|cell| cell.iter().all(|v| job_variables[*name].contains(v)))
+                    })
+                    .collect();
+                if selected.is_empty() {
+                    return Err(ParsingError(format!(
+                        "{} needs a matrix cell of {} that does not exist",
+                        job.name, need.job
+                    )));
+                }
+                resolved_needs.extend(selected.into_iter().filter_map(expanded_need));
+            }
+
+            *needs = resolved_needs;
+        }
+        let mut config = Self::new_with_params(jobs, stages, variables.unwrap_or(vec![]));
+        config.secrets = secrets;
+        config.workspace = workspace;
+        Ok(config)
+    }
+
+    // Mount sources have to be within one of `mount_roots`, so a pipeline file
+    // can't mount arbitrary host paths. Sources are replaced by their canonical
+    // path, which also resolves symlinks pointing out of the roots
+    pub fn resolve_mounts(&mut self, mount_roots: &[String]) -> Result<(), PipelineError> {
+        let mut roots = vec![];
+        for root in mount_roots {
+            roots.push(
+                std::fs::canonicalize(root)
+                    .map_err(|e| MountError(root.clone(), format!("mount root: {}", e)))?,
+            );
+        }
+
+        for job in self.jobs.iter_mut() {
+            for mount in job.mounts.iter_mut() {
+                let target = Path::new(mount.target.as_str());
+                if !target.is_absolute() {
+                    return Err(MountError(
+                        mount.target.clone(),
+                        format!("{}: target should be an absolute path", job.name),
+                    ));
+                }
+                if target.starts_with(CONTAINER_WORKSPACE) {
+                    return Err(MountError(
+                        mount.target.clone(),
+                        format!(
+                            "{}: target should be outside {}",
+                            job.name, CONTAINER_WORKSPACE
+                        ),
+                    ));
+                }
+
+                let source = std::fs::canonicalize(mount.source.as_str()).map_err(|e| {
+                    MountError(mount.source.clone(), format!("{}: {}", job.name, e))
+                })?;
+                if !roots.iter().any(|root| source.starts_with(root)) {
+                    return Err(MountError(
+                        mount.source.clone(),
+                        format!(
+                            "{}: source is not within an allowed mount root, allow it with --mount-root",
+                            job.name
+                        ),
+                    ));
+                }
+                mount.source = source.display().to_string();
+
+                // Both end up in `--mount type=bind,source=..,target=..`, where a
+                // comma or `=` would add options of their own
+                if let Some(path) = [&mount.source, &mount.target]
+                    .into_iter()
+                    .find(|path| path.contains([',', '=']))
+                {
+                    return Err(MountError(
+                        path.clone(),
+                        format!("{}: mount paths should not contain ',' or '='", job.name),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every job has to exist before the pipeline runs, otherwise the jobs
+    // needing it would never start. Optional needs on missing jobs are dropped
+    pub fn check_needs(&mut self) -> Result<(), PipelineError> {
+        let job_names: HashSet<String> = self.jobs.iter().map(|j| j.name.clone()).collect();
+        for job in self.jobs.iter_mut() {
+            let Some(ref mut needs) = job.needs else {
+                continue;
+            };
+            if let Some(need) = needs
+                .iter()
+                .find(|need| !need.optional && !job_names.contains(&need.job))
+            {
+                return Err(ParsingError(format!(
+                    "{} needs unknown job {}",
+                    job.name, need.job
+                )));
+            }
+            needs.retain(|need| job_names.contains(&need.job));
+
+            let mut seen = HashSet::new();
+            if let Some(need) = needs.iter().find(|need| !seen.insert(need.job.as_str())) {
+                return Err(ParsingError(format!(
+                    "{} needs {} more than once",
+                    job.name, need.job
+                )));
+            }
+        }
+
+        // Jobs are scheduled once everything before them is done, so a cycle
+        // would never start. Repeatedly drop the jobs whose predecessors are all
+        // gone; whatever remains is part of a cycle
+        let mut jobs_before: HashMap<&str, Vec<String>> = self
+            .jobs
+            .iter()
+            .map(|job| (job.name.as_str(), self.get_jobs_before(job)))
+            .collect();
+        loop {
+            let done: Vec<&str> = jobs_before
+                .iter()
+                .filter(|(_, before)| before.iter().all(|b| !jobs_before.contains_key(b.as_str())))
+                .map(|(name, _)| *name)
+                .collect();
+            if done.is_empty() {
+                break;
+            }
+            for name in done {
+                jobs_before.remove(name);
+            }
+        }
+        if let Some(name) = jobs_before.keys().min() {
+            return Err(ParsingError(format!(
+                "{} needs itself through a cycle",
+                name
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Jobs with `needs` run as soon as those are done. All others wait for the
+    // jobs of earlier stages
+    fn get_jobs_before(&self, job: &JobConfig) -> Vec<String> {
+        if let Some(ref needs) = job.needs {
+            return needs.iter().map(|need| need.job.clone()).collect();
+        }
+
+        let stage_index = |job: &JobConfig| {
+            let stage = job.stage.as_ref()?;
+            self.stages.as_ref()?.iter().position(|s| s == stage)
+        };
+        let Some(index) = stage_index(job) else {
+            return vec![];
+        };
+        self.jobs
+            .iter()
+            .filter(|j| stage_index(j).is_some_and(|i| i < index))
+            .map(|j| j.name.clone())
+            .collect()
+    }
+
+    // Decides which jobs each job loads artifacts from. Explicit dependencies
+    // have to run before the job, otherwise their artifacts wouldn't exist yet
+    pub fn resolve_dependencies(&mut self) -> Result<(), PipelineError> {
+        let mut resolved = vec![];
+        for job in self.jobs.iter() {
+            let jobs_before = self.get_jobs_before(job);
+            let dependencies = match (&job.dependencies, &job.needs) {
+                (Some(dependencies), _) => {
+                    if let Some(dependency) = dependencies.iter().find(|d| !jobs_before.contains(d))
+                    {
+                        return Err(ParsingError(format!(
+                            "{} depends on {} which does not run before it",
+                            job.name, dependency
+                        )));
+                    }
+                    dependencies.clone()
+                }
+                (None, Some(needs)) => needs
+                    .iter()
+                    .filter(|need| need.artifacts)
+                    .map(|need| need.job.clone())
+                    .collect(),
+                (None, None) => jobs_before,
+            };
+            resolved.push(dependencies);
+        }
+
+        for (job, dependencies) in self.jobs.iter_mut().zip(resolved) {
+            job.dependencies = Some(dependencies);
+        }
+        Ok(())
+    }
+
+    // Reads all secrets declared in the config. Secrets from `secrets_file` take
+    // precedence over ones with the same name declared in the config
+    pub fn resolve_secrets(
+        &self,
+        secrets_file: Option<&str>,
+    ) -> Result<Vec<Secret>, PipelineError> {
+        let mut secrets = vec![];
+        for secret_file in &self.secrets {
+            secrets.push(secret_file.read()?);
+        }
+
+        if let Some(secrets_file) = secrets_file {
+            for secret in load_secrets_file(secrets_file)? {
+                secrets.retain(|s: &Secret| s.key != secret.key);
+                secrets.push(secret);
+            }
+        }
+
+        Ok(secrets)
+    }
+}
+
+pub struct Pipeline {
+    file_path: String,
+    secrets_file: Option<String>,
+    // Keeps the artifacts of the run around after it finishes instead of
+    // deleting them
+    keep_artifacts: bool,
+    // Where the JUnit reports of all jobs are merged into
+    junit_report: String,
+    // Where the coverage and test counts of all jobs are written as JSON
+    summary_json: Option<String>,
+    // Overrides the workspace of the pipeline file
+    workspace: Option<String>,
+    // Host directories jobs may mount paths from
+    mount_roots: Vec<String>,
+}
+
+impl Pipeline {
+    pub fn new_with_params(
+        file_path: String,
+        secrets_file: Option<String>,
+        keep_artifacts: bool,
+        junit_report: String,
+        summary_json: Option<String>,
+        workspace: Option<String>,
+        mount_roots: Vec<String>,
+    ) -> Self {
+        Self {
+            file_path,
+            secrets_file,
+            keep_artifacts,
+            junit_report,
+            summary_json,
+            workspace,
+            mount_roots,
+        }
+    }
+
+    // Caches outlive runs, so they are kept in the user's data dir rather than in
+    // a shared /tmp dir, where other users could read or replace them
+    fn get_cache_location() -> String {
+        let data_dir = match (std::env::var_os("XDG_DATA_HOME"), std::env::var_os("HOME")) {
+            (Some(data_home), _) if !data_home.is_empty() => PathBuf::from(data_home),
+            (_, Some(home)) => PathBuf::from(home).join(".local/share"),
+            (_, None) => {
+                std::env::temp_dir().join(format!(".pipeline-runner-{}", unsafe { libc::getuid() }))
+            }
+        };
+        data_dir.join(CACHE_DIR).display().to_string()
+    }
+
+    fn get_artifact_manager(run_id: &str) -> ArtifactManager {
+        ArtifactManager::new_with_params(
+            DEFAULT_WORKSPACE.to_string(),
+            DEFAULT_ARTIFACT_LOCATION.to_string(),
+            run_id.to_string(),
+        )
+    }
+
+    // Docker treats relative bind mount sources as volume names, so the
+    // workspace is created if needed and made absolute. A relative --workspace
+    // is relative to the current directory, the config's was already resolved
+    // against the pipeline file
+    fn resolve_workspace(workspace: &str) -> Result<String, PipelineError> {
+        let workspace_error =
+            |e: std::io::Error| WorkspaceError(workspace.to_string(), e.to_string());
+        std::fs::create_dir_all(workspace).map_err(workspace_error)?;
+        let workspace = std::fs::canonicalize(workspace).map_err(workspace_error)?;
+        Ok(workspace.display().to_string())
+    }
+
+    fn get_execution_order<'a>(
+        config: &ParserConfig,
+        jobs: Vec<&'a JobConfig>,
+    ) -> Vec<Vec<&'a JobConfig>> {
+        let mut execution_order = vec![];
+        let mut jobs_by_name = HashMap::new();
+        let mut graph = HashMap::new();
+        let mut job_names = vec![];
+        for job in jobs {
+            let job_name = job.name.clone();
+            job_names.push(job_name.clone());
+            jobs_by_name.insert(job_name.clone(), job);
+
+            for deps in config.get_jobs_before(job) {
+                let entry = graph.entry(job_name.clone());
+                entry
+                    .and_modify(|d: &mut Vec<String>| d.push(deps.clone()))
+                    .or_insert(vec![deps.clone()]);
+            }
+        }
+
+        while !jobs_by_name.is_empty() {
+            let mut runnable_jobs = vec![];
+
+            for curr_job_name in jobs_by_name.keys() {
+                let deps = graph.get(curr_job_name);
+
+                // This job has no dependencies, so we can run it now
+                if deps.is_none_or(|deps| deps.is_empty()) {
+                    runnable_jobs.push(curr_job_name.clone());
+                }
+            }
+
+            let mut parallel_jobs = vec![];
+            for runnable_job in runnable_jobs.iter() {
+                let job = jobs_by_name.remove(runnable_job).expect("should exist");
+                parallel_jobs.push(job);
+            }
+
+            parallel_jobs.sort_by(|a, b| a.name.cmp(&b.name));
+            execution_order.push(parallel_jobs);
+
+            for curr_job_name in job_names.iter() {
+                let deps = graph.get_mut(curr_job_name);
+
+                // Remove the runnable jobs from deps of other jobs
+                if let Some(deps) = deps {
+                    deps.retain(|d| !runnable_jobs.contains(d));
+                }
+            }
+        }
+
+        execution_order
+    }
+
+    async fn execute_job(
+        job: JobConfig,
+        artifact_manager: ArtifactManager,
+        cache_manager: CacheManager,
+        secrets: Vec<Secret>,
+        cancellation: Cancellation,
+    ) -> Option<JobReport> {
+        // Jobs that haven't started yet are cancelled, unless they clean up
+        if cancellation.is_cancelled() && job.when != JobWhen::Always {
+            println!("[{}] CANCELLED", job.name);
+            return None;
+        }
+
+        let job_name = job.name.clone();
+        match tokio::task::spawn_blocking(|| {
+            let executor = Executor::new_with_params(
+                artifact_manager.workspace.clone(),
+                secrets,
+                cancellation,
+            );
+            let job = job;
+            let artifact_manager = artifact_manager;
+            let cache_manager = cache_manager;
+            match executor.run(&job, &artifact_manager, &cache_manager) {
+                Ok(report) => Some(report),
+                Err(err) => {
+                    println!("{} job failed| {}", job.name, err);
+                    None
+                }
+            }
+        })
+        .await
+        {
+            Ok(report) => report,
+            Err(e) => {
+                println!("{} job failed| {}", job_name, e);
+                None
+            }
+        }
+    }
+
+    // Prints the test results of every job that reported any and writes them
+    // all into a single JUnit report
+    fn report_tests(reports: &[JobReport], junit_report: &str) {
+        let reports: Vec<JobReport> = reports
+            .iter()
+            .filter(|r| !r.test_suites.is_empty())
+            .cloned()
+            .collect();
+        if reports.is_empty() {
+            return;
+        }
+
+        println!("Test summary");
+        let mut total = TestCounts::default();
+        for report in reports.iter() {
+            let counts = report.test_counts();
+            println!(
+                "  {}: {} passed, {} failed, {} skipped",
+                report.job, counts.passed, counts.failed, counts.skipped
+            );
+            total.add(counts);
+        }
+        println!(
+            "  Total: {} passed, {} failed, {} skipped",
+            total.passed, total.failed, total.skipped
+        );
+
+        let failed: Vec<(&str, &TestCase)> = reports
+            .iter()
+            .flat_map(|r| r.failed_tests().into_iter().map(|t| (r.job.as_str(), t)))
+            .collect();
+        if !failed.is_empty() {
+            println!("Failed tests");
+            for (job, test) in failed {
+                let TestStatus::Failed(ref message) = test.status else {
+                    continue;
+                };
+                println!("  [{}] {}::{}: {}", job, test.classname, test.name, message);
+            }
+        }
+
+        match write_junit(Path::new(junit_report), &reports) {
+            Ok(_) => println!("JUnit report written to {}", junit_report),
+            Err(e) => println!("JUnit report could not be written: {}", e),
+        }
+    }
+
+    fn report_coverage(reports: &[JobReport]) {
+        let Some(total) = pipeline_coverage(reports) else {
+            return;
+        };
+
+        println!("Coverage summary");
+        for report in reports.iter() {
+            if let Some(coverage) = report.coverage {
+                println!("  {}: {}%", report.job, coverage);
+            }
+        }
+        println!("  Pipeline: {:.2}%", total);
+    }
+
+    // Variables are substituted right before a job runs, since the dotenv
+    // reports of the jobs it depends on are only known by then
+    fn prepare_job(
+        config: &ParserConfig,
+        job: &JobConfig,
+        artifact_manager: &ArtifactManager,
+    ) -> JobConfig {
+        let mut dotenv: Vec<Variable> = vec![];
+        for dependency in job.dependencies.iter().flatten() {
+            match artifact_manager.load_dotenv(dependency.as_str()) {
+                Ok(variables) => {
+                    for var in variables {
+                        dotenv.retain(|v| v.key != var.key);
+                        dotenv.push(var);
+                    }
+                }
+                Err(e) => println!(
+                    "[{}] dotenv of {} could not be read: {}",
+                    job.name, dependency, e
+                ),
+            }
+        }
+
+        config.substitute_job_config(job, &dotenv)
+    }
+
+    async fn run_internal(
+        config: ParserConfig,
+        secrets: Vec<Secret>,
+        artifact_manager: ArtifactManager,
+        keep_artifacts: bool,
+        junit_report: String,
+        summary_json: Option<String>,
+        cancellation: Cancellation,
+    ) {
+        let cache_manager = CacheManager::new_with_params(
+            artifact_manager.workspace.clone(),
+            Self::get_cache_location(),
+        );
+        let mut reports = vec![];
+        let execution_order = Self::get_execution_order(&config, config.jobs.iter().collect());
+        for parallel_jobs in execution_order {
+            // Jobs are only prepared once the jobs before them finished, so
+            // the dotenv reports of their dependencies exist
+            let mut jobs_set = tokio::task::JoinSet::new();
+            for job in parallel_jobs {
+                jobs_set.spawn(Self::execute_job(
+                    Self::prepare_job(&config, job, &artifact_manager),
+                    artifact_manager.clone(),
+                    cache_manager.clone(),
+                    secrets.clone(),
+                    cancellation.clone(),
+                ));
+            }
+            reports.extend(jobs_set.join_all().await.into_iter().flatten());
+        }
+
+        Self::report_tests(&reports, junit_report.as_str());
+        Self::report_coverage(&reports);
+        if let Some(summary_json) = summary_json {
+            match write_summary(Path::new(summary_json.as_str()), &reports) {
+                Ok(_) => println!("Summary written to {}", summary_json),
+                Err(e) => println!("Summary could not be written: {}", e),
+            }
+        }
+
+        if keep_artifacts {
+            println!("Artifacts kept for run {}", artifact_manager.run_id);
+        } else if let Err(e) = artifact_manager.cleanup() {
+            println!("Artifact cleanup failed: {:?}", e.to_string());
+        }
+    }
+
+    // The first SIGINT or SIGTERM cancels the run: running containers are
+    // stopped and jobs that haven't started are skipped, while the pipeline
+    // still finishes its `when: always` jobs and cleanup. A second one kills
+    // everything and exits right away
+    async fn handle_signals(cancellation: Cancellation) {
+        let Ok(mut sigterm) = signal(SignalKind::terminate()) else {
+            println!("SIGTERM handler could not be installed");
+            return;
+        };
+
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+
+            if cancellation.cancel() {
+                println!("Cancelling again, killing all containers");
+                let cancellation = cancellation.clone();
+                let _ = tokio::task::spawn_blocking(move || cancellation.kill_containers()).await;
+                std::process::exit(CANCELLED_EXIT_CODE);
+            }
+
+            println!(
+                "Cancelling pipeline, stopping running containers within {:?}",
+                CANCEL_GRACE_PERIOD
+            );
+            // Not awaited, so a second signal is handled while containers stop
+            let cancellation = cancellation.clone();
+            tokio::task::spawn_blocking(move || cancellation.stop_containers(CANCEL_GRACE_PERIOD));
+        }
+    }
+
+    // Deletes expired artifacts and results. Doesn't need a pipeline file since
+    // artifacts remember when they expire
+    pub fn prune() -> Result<(), PipelineError> {
+        let summary = Self::get_artifact_manager("")
+            .prune()
+            .map_err(PipelineError::ArtifactError)?;
+        println!(
+            "Pruned {} artifacts, {} results and {} blobs, freed {}",
+            summary.artifacts,
+            summary.results,
+            summary.blobs,
+            format_size(summary.freed)
+        );
+        Ok(())
+    }
+
+    // Without a run lists all runs with kept artifacts, without a job the jobs
+    // of the run and otherwise the saved paths of the job
+    pub fn list_artifacts(
+        run_id: Option<&str>,
+        job_name: Option<&str>,
+    ) -> Result<(), PipelineError> {
+        let artifact_manager = Self::get_artifact_manager(run_id.unwrap_or_default());
+        let lines = match (run_id, job_name) {
+            (None, _) => artifact_manager.list_runs(),
+            (Some(_), None) => artifact_manager.list_jobs().map(|jobs| {
+                jobs.into_iter()
+                    .map(|(job, size)| format!("{}  {}", job, format_size(size)))
+                    .collect()
+            }),
+            (Some(_), Some(job_name)) => artifact_manager.list_artifacts(job_name),
+        }
+        .map_err(PipelineError::ArtifactError)?;
+
+        for line in lines {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
+    pub fn get_artifacts(run_id: &str, job_name: &str, output: &str) -> Result<(), PipelineError> {
+        Self::get_artifact_manager(run_id)
+            .get_artifacts(job_name, Path::new(output))
+            .map_err(PipelineError::ArtifactError)?;
+        println!("Artifacts of {} saved to {}", job_name, output);
+        Ok(())
+    }
+
+    pub fn show(&self, job_name: &str) -> Result<(), PipelineError> {
+        // Parsed first so only valid configs are shown
+        ParserConfig::parse_from_file(self.file_path.as_str())?;
+        let job = ParserConfig::merged_job_from_file(self.file_path.as_str(), job_name)?;
+        let mut shown = serde_yml::Mapping::new();
+        shown.insert(job_name.into(), serde_yml::Value::Mapping(job));
+        let yaml = serde_yml::to_string(&shown).map_err(|e| ParsingError(e.to_string()))?;
+        print!("{}", yaml);
+        Ok(())
+    }
+
+    pub fn run(&self) -> Result<(), PipelineError> {
+        let rt = Runtime::new().map_err(|e| RuntimeError(e.to_string()))?;
+        let mut config = ParserConfig::parse_from_file(self.file_path.as_str())?;
+        config.check_needs()?;
+        config.resolve_dependencies()?;
+        config.resolve_mounts(&self.mount_roots)?;
+        let secrets = config.resolve_secrets(self.secrets_file.as_deref())?;
+        warn_unmasked_secrets(&secrets);
+        let workspace = self
+            .workspace
+            .as_deref()
+            .or(config.workspace.as_deref())
+            .unwrap_or(DEFAULT_WORKSPACE);
+        let artifact_manager = ArtifactManager {
+            workspace: Self::resolve_workspace(workspace)?,
+            ..Self::get_artifact_manager(new_run_id().as_str())
+        };
+        println!("Pipeline run {}", artifact_manager.run_id);
+        println!("Workspace {}", artifact_manager.workspace);
+        let cancellation = Cancellation::new_with_params(artifact_manager.run_id.clone());
+        rt.block_on(async {
+            let signals = tokio::spawn(Self::handle_signals(cancellation.clone()));
+            Self::run_internal(
+                config,
+                secrets,
+                artifact_manager,
+                self.keep_artifacts,
+                self.junit_report.clone(),
+                self.summary_json.clone(),
+                cancellation.clone(),
+            )
+            .await;
+            signals.abort();
+        });
+
+        if cancellation.is_cancelled() {
+            return Err(Cancelled);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::job::ArtifactsReports;
+
+    fn needs(jobs: &[&str]) -> Option<Vec<Need>> {
+        Some(
+            jobs.iter()
+                .map(|job| Need::new_with_params(job.to_string(), true, false))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_str() {
+        let config = r#"
+build-job:
+  image: python:3.11
+  artifacts:
+    paths:
+      - dist
+  needs:
+    - unit-tests
+    - integration-tests
+  script:
+    - echo "Building application..."
     - python --version
     - pip install --quiet build
     - echo "Build complete!"
         "#;
         let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
         assert_eq!(
-            parser_config,
-            ParserConfig::new_with_params(
-                vec![JobConfig {
-                    name: "build-job".to_string(),
-                    image: "python:3.11".to_string(),
-                    script: vec![
-                        "echo \"Building application...\"".to_string(),
-                        "python --version".to_string(),
-                        "pip install --quiet build".to_string(),
-                        "echo \"Build complete!\"".to_string(),
-                    ],
-                    stage: None,
-                    needs: Some(vec![
-                        "unit-tests".to_string(),
-                        "integration-tests".to_string()
-                    ]),
-                    artifacts: Some(vec!["dist".to_string()]),
-                }],
-                None
-            )
+            parser_config,
+            ParserConfig::new_with_params(
+                vec![JobConfig {
+                    name: "build-job".to_string(),
+                    image: ImageConfig::new_with_params("python:3.11".to_string()),
+                    script: vec![
+                        "echo \"Building application...\"".to_string(),
+                        "python --version".to_string(),
+                        "pip install --quiet build".to_string(),
+                        "echo \"Build complete!\"".to_string(),
+                    ],
+                    stage: None,
+                    needs: needs(&["unit-tests", "integration-tests"]),
+                    artifacts: Some(ArtifactsConfig {
+                        paths: vec!["dist".to_string()],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }],
+                None,
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_from_file() {
+        let file_path = "samples/simple-single-job.yml";
+        let parser_config =
+            ParserConfig::parse_from_file(file_path).expect("parsing should suceed");
+        assert_eq!(
+            parser_config,
+            ParserConfig::new_with_params(
+                vec![JobConfig {
+                    name: "build-job".to_string(),
+                    image: ImageConfig::new_with_params("python:3.11".to_string()),
+                    script: vec![
+                        "echo \"Building application...\"".to_string(),
+                        "python --version".to_string(),
+                        "pip install --quiet build".to_string(),
+                        "echo \"Build complete!\"".to_string(),
+                    ],
+                    stage: None,
+                    needs: None,
+                    artifacts: None,
+                    ..Default::default()
+                }],
+                None,
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_secrets() {
+        let config = r#"
+secrets:
+  API_TOKEN: ./secrets/api_token
+build-job:
+  image: python:3.11
+  script:
+    - echo "${API_TOKEN}"
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.secrets,
+            vec![SecretFile::new_with_params(
+                "API_TOKEN".to_string(),
+                "./secrets/api_token".to_string()
+            )]
+        );
+
+        // Secrets should never be substituted into the script
+        let job = parser_config.substitute_job_config(&parser_config.jobs[0], &[]);
+        assert_eq!(job.script, vec!["echo \"${API_TOKEN}\"".to_string()]);
+
+        // Secret files are relative to the file defining them
+        let sources = HashMap::from([("secrets".to_string(), "ci/pipeline.yml".to_string())]);
+        let parser_config = ParserConfig::load_yaml(config)
+            .and_then(|config| ParserConfig::parse_mapping(config, &sources))
+            .expect("parsing should suceed");
+        assert_eq!(parser_config.secrets[0].path, "ci/./secrets/api_token");
+    }
+
+    #[test]
+    fn test_parse_default_section() {
+        let config = r#"
+default:
+  image: python:3.11
+  before_script:
+    - pip install -r requirements.txt
+  after_script:
+    - rm -rf .cache
+  timeout: 1h 30m
+  retry:
+    max: 2
+unit-tests:
+  script:
+    - pytest
+lint:
+  image: alpine:latest
+  before_script: []
+  timeout: 300
+  retry: 0
+  script:
+    - ./lint.sh
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.jobs,
+            vec![
+                JobConfig {
+                    name: "unit-tests".to_string(),
+                    image: ImageConfig::new_with_params("python:3.11".to_string()),
+                    script: vec!["pytest".to_string()],
+                    before_script: vec!["pip install -r requirements.txt".to_string()],
+                    after_script: vec!["rm -rf .cache".to_string()],
+                    timeout: Some(Duration::from_secs(90 * 60)),
+                    retry: 2,
+                    ..Default::default()
+                },
+                JobConfig {
+                    name: "lint".to_string(),
+                    image: ImageConfig::new_with_params("alpine:latest".to_string()),
+                    script: vec!["./lint.sh".to_string()],
+                    before_script: vec![],
+                    after_script: vec!["rm -rf .cache".to_string()],
+                    timeout: Some(Duration::from_secs(300)),
+                    retry: 0,
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let too_many = config.replace("retry: 0", "retry: 4294967296");
+        assert_eq!(
+            ParserConfig::parse_str(too_many.as_str()),
+            Err(ParsingError(
+                "retry should be at most 4294967295".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_extends() {
+        let config = r#"
+variables:
+  PYTHON_VERSION: "3.11"
+.python-base:
+  image: python:${PYTHON_VERSION}
+  before_script:
+    - pip install -r requirements.txt
+  variables:
+    PIP_CACHE_DIR: .cache/pip
+    LOG_LEVEL: info
+.debug:
+  variables:
+    LOG_LEVEL: debug
+unit-tests:
+  extends:
+    - .python-base
+    - .debug
+  variables:
+    PYTHON_VERSION: "3.12"
+  script:
+    - pytest
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(parser_config.jobs.len(), 1);
+
+        let job = parser_config.substitute_job_config(&parser_config.jobs[0], &[]);
+        assert_eq!(
+            job,
+            JobConfig {
+                name: "unit-tests".to_string(),
+                image: ImageConfig::new_with_params("python:3.12".to_string()),
+                script: vec!["pytest".to_string()],
+                before_script: vec!["pip install -r requirements.txt".to_string()],
+                variables: vec![
+                    Variable {
+                        key: "PIP_CACHE_DIR".to_string(),
+                        value: ".cache/pip".to_string()
+                    },
+                    Variable {
+                        key: "LOG_LEVEL".to_string(),
+                        value: "debug".to_string()
+                    },
+                    Variable {
+                        key: "PYTHON_VERSION".to_string(),
+                        value: "3.12".to_string()
+                    },
+                ],
+                ..Default::default()
+            }
+        );
+
+        // `show` prints the merged config as written, before substitution
+        let merged = ParserConfig::load_yaml(config)
+            .and_then(|config| ParserConfig::merged_job(&config, "unit-tests"))
+            .expect("merging should succeed");
+        assert_eq!(
+            serde_yml::to_string(&merged).expect("should serialize"),
+            r#"image: python:${PYTHON_VERSION}
+before_script:
+- pip install -r requirements.txt
+variables:
+  PIP_CACHE_DIR: '.cache/pip'
+  LOG_LEVEL: debug
+  PYTHON_VERSION: '3.12'
+script:
+- pytest
+"#
+        );
+        assert_eq!(
+            ParserConfig::load_yaml(config)
+                .and_then(|config| ParserConfig::merged_job(&config, ".debug")),
+            Err(JobNotFound(".debug".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_extends_errors() {
+        let cycle = r#"
+.a:
+  extends: .b
+.b:
+  extends: .a
+job:
+  extends: .a
+  image: alpine:latest
+  script:
+    - echo
+        "#;
+        assert_eq!(
+            ParserConfig::parse_str(cycle),
+            Err(ParsingError(
+                "extends cycle detected: job -> .a -> .b -> .a".to_string()
+            ))
+        );
+
+        let unknown = r#"
+job:
+  extends: .missing
+  image: alpine:latest
+  script:
+    - echo
+        "#;
+        assert_eq!(
+            ParserConfig::parse_str(unknown),
+            Err(ParsingError("job extends unknown job .missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_anchors_and_merge_keys() {
+        let config = r#"
+variables: &global_vars
+  APP_VERSION: "1.0.0"
+.defaults: &defaults
+  image: python:3.11
+  before_script: &setup
+    - pip install -r requirements.txt
+  script:
+    - echo "default"
+.chained: &chained
+  <<: *defaults
+  variables:
+    <<: *global_vars
+    LOG_LEVEL: debug
+unit-tests:
+  <<: *defaults
+  script:
+    - pytest
+lint:
+  <<: *defaults
+  image: alpine:latest
+build:
+  <<: *chained
+  after_script: *setup
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        let jobs: HashMap<&str, &JobConfig> = parser_config
+            .jobs
+            .iter()
+            .map(|j| (j.name.as_str(), j))
+            .collect();
+
+        let unit_tests = jobs["unit-tests"];
+        assert_eq!(unit_tests.image.name, "python:3.11");
+        assert_eq!(unit_tests.script, vec!["pytest".to_string()]);
+        assert_eq!(
+            unit_tests.before_script,
+            vec!["pip install -r requirements.txt".to_string()]
+        );
+
+        let lint = jobs["lint"];
+        assert_eq!(lint.image.name, "alpine:latest");
+        assert_eq!(lint.script, vec!["echo \"default\"".to_string()]);
+
+        let build = jobs["build"];
+        assert_eq!(build.image.name, "python:3.11");
+        assert_eq!(build.after_script, build.before_script);
+        assert_eq!(
+            build.variables,
+            vec![
+                Variable {
+                    key: "LOG_LEVEL".to_string(),
+                    value: "debug".to_string()
+                },
+                Variable {
+                    key: "APP_VERSION".to_string(),
+                    value: "1.0.0".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_includes() {
+        let file_path = "samples/simple-include.yml";
+        let parser_config =
+            ParserConfig::parse_from_file(file_path).expect("parsing should suceed");
+        let jobs: Vec<JobConfig> = parser_config
+            .jobs
+            .iter()
+            .map(|j| parser_config.substitute_job_config(j, &[]))
+            .collect();
+
+        assert_eq!(
+            jobs.iter().map(|j| j.name.as_str()).collect::<Vec<&str>>(),
+            vec!["lint", "unit-tests"]
+        );
+        assert!(jobs.iter().all(|j| j.image.name == "python:3.12"));
+        assert!(
+            jobs.iter()
+                .all(|j| j.before_script == vec!["python --version".to_string()])
+        );
+        assert_eq!(
+            jobs[1].script,
+            vec![
+                "echo \"Running unit tests...\"".to_string(),
+                "echo \"Unit tests passed!\"".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_include_errors() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let dir = temp_dir.path();
+        let write = |name: &str, contents: &str| {
+            std::fs::write(dir.join(name), contents).expect("should write config");
+            dir.join(name).display().to_string()
+        };
+
+        let a = write("a.yml", "include: b.yml\n");
+        write("b.yml", "include: a.yml\n");
+        let Err(ParsingError(msg)) = ParserConfig::parse_from_file(a.as_str()) else {
+            panic!("include cycle should fail");
+        };
+        assert!(msg.starts_with("include cycle detected"), "{}", msg);
+
+        let main = write("main.yml", "include: broken.yml\n");
+        let broken = write(
+            "broken.yml",
+            "job:\n  image: alpine:latest\n  stage: 3\n  script: []\n",
+        );
+        assert_eq!(
+            ParserConfig::parse_from_file(main.as_str()),
+            Err(ParsingError(format!(
+                "{}: stage should be a string",
+                broken
+            )))
+        );
+
+        let missing = write("missing.yml", "include: nothing/*.yml\n");
+        assert_eq!(
+            ParserConfig::parse_from_file(missing.as_str()),
+            Err(ParsingError(format!(
+                "{}: include nothing/*.yml did not match any files",
+                missing
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_parallel() {
+        let config = r#"
+unit-tests:
+  image: python:3.11
+  parallel: 3
+  script:
+    - pytest --shard ${CI_NODE_INDEX}
+report:
+  image: alpine:latest
+  needs:
+    - unit-tests
+  script:
+    - ls reports
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        let shard_names = vec![
+            "unit-tests 1/3".to_string(),
+            "unit-tests 2/3".to_string(),
+            "unit-tests 3/3".to_string(),
+        ];
+        assert_eq!(
+            parser_config
+                .jobs
+                .iter()
+                .map(|j| j.name.clone())
+                .collect::<Vec<String>>(),
+            [shard_names.clone(), vec!["report".to_string()]].concat()
+        );
+
+        let shard = parser_config.substitute_job_config(&parser_config.jobs[1], &[]);
+        assert_eq!(shard.script, vec!["pytest --shard 2".to_string()]);
+        assert_eq!(
+            shard.variables,
+            vec![
+                Variable {
+                    key: "CI_NODE_INDEX".to_string(),
+                    value: "2".to_string()
+                },
+                Variable {
+                    key: "CI_NODE_TOTAL".to_string(),
+                    value: "3".to_string()
+                },
+            ]
+        );
+        let shard_names: Vec<&str> = shard_names.iter().map(String::as_str).collect();
+        assert_eq!(parser_config.jobs[3].needs, needs(&shard_names));
+
+        let invalid = "job:\n  image: alpine\n  parallel: 0\n  script: []\n";
+        assert_eq!(
+            ParserConfig::parse_str(invalid),
+            Err(ParsingError(
+                "parallel should be a number between 1 and 200".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_parallel_matrix() {
+        let config = r#"
+test:
+  image: python:${PYTHON_VERSION}
+  parallel:
+    matrix:
+      - PYTHON_VERSION: ["3.11", "3.12"]
+        DATABASE: [postgres, mysql]
+      - PYTHON_VERSION: "3.10"
+        DATABASE: sqlite
+  script:
+    - pytest --db ${DATABASE}
+report:
+  image: alpine:latest
+  needs:
+    - job: test
+      parallel:
+        matrix:
+          - DATABASE: postgres
+    - "test: [3.10, sqlite]"
+  script:
+    - ls reports
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(
+            parser_config
+                .jobs
+                .iter()
+                .map(|j| j.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec![
+                "test: [3.11, postgres]",
+                "test: [3.11, mysql]",
+                "test: [3.12, postgres]",
+                "test: [3.12, mysql]",
+                "test: [3.10, sqlite]",
+                "report",
+            ]
+        );
+
+        let job = parser_config.substitute_job_config(&parser_config.jobs[3], &[]);
+        assert_eq!(job.image.name, "python:3.12");
+        assert_eq!(job.script, vec!["pytest --db mysql".to_string()]);
+
+        assert_eq!(
+            parser_config.jobs[5].needs,
+            needs(&[
+                "test: [3.11, postgres]",
+                "test: [3.12, postgres]",
+                "test: [3.10, sqlite]",
+            ])
+        );
+
+        let missing_cell = r#"
+test:
+  image: alpine:latest
+  parallel:
+    matrix:
+      - DATABASE: [postgres, mysql]
+  script: []
+report:
+  image: alpine:latest
+  needs:
+    - job: test
+      parallel:
+        matrix:
+          - DATABASE: sqlite
+  script: []
+        "#;
+        assert_eq!(
+            ParserConfig::parse_str(missing_cell),
+            Err(ParsingError(
+                "report needs a matrix cell of test that does not exist".to_string()
+            ))
+        );
+
+        // Each entry selects its own cells, even when they need the same job
+        let separate_cells = r#"
+test:
+  image: alpine:latest
+  parallel:
+    matrix:
+      - DATABASE: [postgres, mysql, sqlite]
+  script: []
+report:
+  image: alpine:latest
+  needs:
+    - job: test
+      parallel:
+        matrix:
+          - DATABASE: postgres
+    - job: test
+      artifacts: false
+      parallel:
+        matrix:
+          - DATABASE: [postgres, mysql]
+  script: []
+        "#;
+        let mut parser_config =
+            ParserConfig::parse_str(separate_cells).expect("parsing should suceed");
+        parser_config.check_needs().expect("needs should be valid");
+        assert_eq!(
+            parser_config.jobs[3].needs,
+            Some(vec![
+                Need::new_with_params("test: [postgres]".to_string(), true, false),
+                Need::new_with_params("test: [mysql]".to_string(), false, false),
+            ])
+        );
+        assert_eq!(
+            Pipeline::get_execution_order(&parser_config, parser_config.jobs.iter().collect())
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_parse_artifacts() {
+        let config = r#"
+build:
+  image: python:3.11
+  script:
+    - python -m build
+  artifacts:
+    paths:
+      - dist/**/*.whl
+    exclude:
+      - dist/cache
+    format: tar.zst
+    when: always
+    expire_in: 1 week
+    reports:
+      junit: reports/*.xml
+      dotenv: build.env
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.jobs[0].artifacts,
+            Some(ArtifactsConfig {
+                paths: vec!["dist/**/*.whl".to_string()],
+                exclude: vec!["dist/cache".to_string()],
+                format: ArtifactFormat::TarZst,
+                when: ArtifactsWhen::Always,
+                expire_in: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+                reports: ArtifactsReports {
+                    junit: vec!["reports/*.xml".to_string()],
+                    dotenv: Some("build.env".to_string()),
+                },
+            })
+        );
+
+        // Reports don't need any paths
+        let reports_only = config.replace("    paths:\n      - dist/**/*.whl\n", "");
+        let parser_config =
+            ParserConfig::parse_str(reports_only.as_str()).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.jobs[0]
+                .artifacts
+                .as_ref()
+                .map(|a| a.paths.is_empty()),
+            Some(true)
+        );
+
+        let never = config.replace("1 week", "never");
+        let parser_config = ParserConfig::parse_str(never.as_str()).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.jobs[0]
+                .artifacts
+                .as_ref()
+                .and_then(|a| a.expire_in),
+            None
+        );
+
+        let invalid = config.replace("always", "sometimes");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "artifacts when should be one of on_success, on_failure or always".to_string()
+            ))
+        );
+
+        let invalid = config.replace("1 week", "soon");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "artifacts expire_in should be a duration".to_string()
+            ))
+        );
+
+        let invalid = config.replace("build.env", "[a, b]");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "artifacts reports dotenv should be a string".to_string()
+            ))
+        );
+
+        let invalid = config.replace("tar.zst", "rar");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "artifacts format should be one of files, tar, tar.gz or tar.zst".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_substitute_dotenv() {
+        let config = r#"
+variables:
+  VERSION: "0.0.0"
+  REGISTRY: registry.local
+build:
+  image: alpine
+  script:
+    - ./build.sh
+deploy:
+  image: ${REGISTRY}/deployer:${VERSION}
+  variables:
+    VERSION: "0.0.1"
+    TARGET: staging
+  script:
+    - ./deploy.sh ${VERSION} ${TARGET} ${IMAGE_TAG}
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        let dotenv = vec![
+            Variable {
+                key: "VERSION".to_string(),
+                value: "1.2.3".to_string(),
+            },
+            Variable {
+                key: "IMAGE_TAG".to_string(),
+                value: "v1.2.3".to_string(),
+            },
+        ];
+
+        let job = parser_config.substitute_job_config(&parser_config.jobs[1], &dotenv);
+        assert_eq!(job.image.name, "registry.local/deployer:1.2.3");
+        assert_eq!(job.script, vec!["./deploy.sh 1.2.3 staging v1.2.3"]);
+
+        let job = parser_config.substitute_job_config(&parser_config.jobs[1], &[]);
+        assert_eq!(job.script, vec!["./deploy.sh 0.0.1 staging ${IMAGE_TAG}"]);
+    }
+
+    #[test]
+    fn test_prepare_job_with_dotenv_of_needs() {
+        let config = r#"
+build:
+  image: alpine
+  script:
+    - ./build.sh
+  artifacts:
+    reports:
+      dotenv: build.env
+deploy:
+  image: alpine
+  needs:
+    - build
+  script:
+    - ./deploy.sh ${VERSION}
+        "#;
+        let mut parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        parser_config
+            .resolve_dependencies()
+            .expect("dependencies should resolve");
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let artifact_manager = ArtifactManager::new_with_params(
+            temp_dir.path().join("workspace").display().to_string(),
+            temp_dir.path().join("artifacts").display().to_string(),
+            "1-1".to_string(),
+        );
+
+        // Without stages, deploy still waits for build and its dotenv report
+        let order: Vec<Vec<&str>> =
+            Pipeline::get_execution_order(&parser_config, parser_config.jobs.iter().collect())
+                .iter()
+                .map(|jobs| jobs.iter().map(|j| j.name.as_str()).collect())
+                .collect();
+        assert_eq!(order, vec![vec!["build"], vec!["deploy"]]);
+
+        artifact_manager
+            .save_dotenv(
+                "build",
+                &[Variable {
+                    key: "VERSION".to_string(),
+                    value: "1.2.3".to_string(),
+                }],
+            )
+            .expect("saving dotenv should succeed");
+        let job = Pipeline::prepare_job(&parser_config, &parser_config.jobs[1], &artifact_manager);
+        assert_eq!(job.script, vec!["./deploy.sh 1.2.3"]);
+    }
+
+    #[test]
+    fn test_parse_needs_objects() {
+        let config = r#"
+build:
+  image: python:3.11
+  parallel: 2
+  script:
+    - python -m build
+lint:
+  image: python:3.11
+  script:
+    - ruff check
+deploy:
+  image: alpine
+  needs:
+    - job: build
+      artifacts: false
+    - lint
+    - job: docs
+      optional: true
+  script:
+    - ./deploy.sh
+        "#;
+        let mut parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        parser_config
+            .check_needs()
+            .expect("optional needs on missing jobs should be ignored");
+        assert_eq!(
+            parser_config.jobs[3].needs,
+            Some(vec![
+                Need::new_with_params("build 1/2".to_string(), false, false),
+                Need::new_with_params("build 2/2".to_string(), false, false),
+                Need::new_with_params("lint".to_string(), true, false),
+            ])
+        );
+
+        let missing = config.replace("      optional: true\n", "");
+        let mut parser_config =
+            ParserConfig::parse_str(missing.as_str()).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.check_needs(),
+            Err(ParsingError("deploy needs unknown job docs".to_string()))
+        );
+
+        let duplicate = config.replace("    - lint\n", "    - lint\n    - lint\n");
+        let mut parser_config =
+            ParserConfig::parse_str(duplicate.as_str()).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.check_needs(),
+            Err(ParsingError("deploy needs lint more than once".to_string()))
+        );
+
+        let cycle = config.replace(
+            "    - ruff check\n",
+            "    - ruff check\n  needs: [deploy]\n",
+        );
+        let mut parser_config =
+            ParserConfig::parse_str(cycle.as_str()).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.check_needs(),
+            Err(ParsingError(
+                "deploy needs itself through a cycle".to_string()
+            ))
+        );
+
+        let invalid = config.replace("optional: true", "optional: maybe");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "needs optional should be a boolean".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_resolve_dependencies() {
+        let config = r#"
+stages:
+  - build
+  - test
+  - deploy
+
+build:
+  stage: build
+  image: python:3.11
+  parallel: 2
+  script:
+    - python -m build
+docs:
+  stage: build
+  image: python:3.11
+  script:
+    - mkdocs build
+unit-tests:
+  stage: test
+  image: python:3.11
+  script:
+    - pytest
+lint:
+  stage: test
+  image: python:3.11
+  dependencies: []
+  script:
+    - ruff check
+deploy:
+  stage: deploy
+  image: alpine
+  dependencies:
+    - build
+  script:
+    - ./deploy.sh
+pages:
+  stage: deploy
+  image: alpine
+  needs:
+    - job: build
+      artifacts: false
+    - docs
+  script:
+    - ./publish.sh
+        "#;
+        let mut parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        parser_config
+            .resolve_dependencies()
+            .expect("dependencies should resolve");
+        let dependencies: Vec<(&str, Vec<&str>)> = parser_config
+            .jobs
+            .iter()
+            .map(|j| {
+                (
+                    j.name.as_str(),
+                    j.dependencies
+                        .iter()
+                        .flatten()
+                        .map(String::as_str)
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            dependencies,
+            vec![
+                ("build 1/2", vec![]),
+                ("build 2/2", vec![]),
+                ("docs", vec![]),
+                ("unit-tests", vec!["build 1/2", "build 2/2", "docs"]),
+                ("lint", vec![]),
+                ("deploy", vec!["build 1/2", "build 2/2"]),
+                ("pages", vec!["docs"]),
+            ]
+        );
+
+        let order: Vec<Vec<&str>> =
+            Pipeline::get_execution_order(&parser_config, parser_config.jobs.iter().collect())
+                .iter()
+                .map(|jobs| jobs.iter().map(|j| j.name.as_str()).collect())
+                .collect();
+        assert_eq!(
+            order,
+            vec![
+                vec!["build 1/2", "build 2/2", "docs"],
+                vec!["lint", "pages", "unit-tests"],
+                vec!["deploy"],
+            ]
+        );
+
+        let invalid = config.replace(
+            "    - build\n  script:\n    - ./deploy.sh",
+            "    - unit-tests\n    - deploy\n  script:\n    - ./deploy.sh",
+        );
+        let mut parser_config =
+            ParserConfig::parse_str(invalid.as_str()).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.resolve_dependencies(),
+            Err(ParsingError(
+                "deploy depends on deploy which does not run before it".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_image_object() {
+        let config = r#"
+variables:
+  PY_VERSION: "3.12"
+build:
+  image:
+    name: python:${PY_VERSION}
+    entrypoint: [""]
+    pull_policy: always
+    docker:
+      platform: linux/arm64
+      user: "1000:1000"
+  script:
+    - python -m build
+lint:
+  image: ruff:latest
+  script:
+    - ruff check
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        let job = parser_config.substitute_job_config(&parser_config.jobs[0], &[]);
+        assert_eq!(
+            job.image,
+            ImageConfig {
+                name: "python:3.12".to_string(),
+                entrypoint: Some(vec!["".to_string()]),
+                pull_policy: PullPolicy::Always,
+                platform: Some("linux/arm64".to_string()),
+                user: Some("1000:1000".to_string()),
+            }
+        );
+        assert_eq!(
+            parser_config.jobs[1].image,
+            ImageConfig::new_with_params("ruff:latest".to_string())
+        );
+
+        let invalid = config.replace("always", "sometimes");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "image pull_policy should be one of always, if-not-present or never".to_string()
+            ))
+        );
+        let invalid = config.replace("name: python", "tag: python");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError("image name should be a string".to_string()))
+        );
+        let invalid = config.replace("user: \"1000:1000\"", "user: [root]");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "image docker user should be a string".to_string()
+            ))
         );
     }
 
     #[test]
-    fn test_parse_from_file() {
-        let file_path = "samples/simple-single-job.yml";
-        let parser_config =
-            ParserConfig::parse_from_file(file_path).expect("parsing should suceed");
+    fn test_parse_container_options() {
+        let config = r#"
+default:
+  run_as_host_user: true
+  cap_drop: [ALL]
+build:
+  image: rust:1.85
+  resources:
+    cpus: 1.5
+    memory: 2g
+  network: none
+  read_only: true
+  script:
+    - cargo build --offline
+lint:
+  image: rust:1.85
+  script:
+    - cargo clippy
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        let build = &parser_config.jobs[0];
         assert_eq!(
-            parser_config,
-            ParserConfig::new_with_params(
-                vec![JobConfig {
-                    name: "build-job".to_string(),
-                    image: "python:3.11".to_string(),
-                    script: vec![
-                        "echo \"Building application...\"".to_string(),
-                        "python --version".to_string(),
-                        "pip install --quiet build".to_string(),
-                        "echo \"Build complete!\"".to_string(),
+            build.resources,
+            ResourcesConfig {
+                cpus: Some(1.5),
+                memory: Some(2 * 1024 * 1024 * 1024),
+            }
+        );
+        assert_eq!(build.network, NetworkMode::None);
+        assert!(build.read_only);
+        assert_eq!(build.cap_drop, vec!["ALL"]);
+        assert!(build.run_as_host_user);
+        let lint = &parser_config.jobs[1];
+        assert_eq!(lint.resources, ResourcesConfig::default());
+        assert_eq!(lint.network, NetworkMode::Bridge);
+        assert!(!lint.read_only);
+        assert!(lint.run_as_host_user);
+
+        for (memory, bytes) in [
+            ("512m", Some(512 * 1024 * 1024)),
+            ("64KB", Some(64 * 1024)),
+            ("1000", Some(1000)),
+            ("0", None),
+            ("1.5g", None),
+            ("lots", None),
+        ] {
+            assert_eq!(ResourcesConfig::parse_memory(memory), bytes, "{}", memory);
+        }
+
+        let invalid = config.replace("memory: 2g", "memory: lots");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "resources memory should be a size like 512m or 2g".to_string()
+            ))
+        );
+        let invalid = config.replace("cpus: 1.5", "cpus: 0");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "resources cpus should be a positive number".to_string()
+            ))
+        );
+        let invalid = config.replace("network: none", "network: host");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "network should be one of none or bridge".to_string()
+            ))
+        );
+        let invalid = config.replace("  read_only: true\n", "  services: [postgres]\n");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "build can't use services without a network".to_string()
+            ))
+        );
+        let invalid = config.replace(
+            "  image: rust:1.85\n  resources",
+            "  image:\n    name: rust:1.85\n    docker:\n      user: root\n  resources",
+        );
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "build can't set both run_as_host_user and an image user".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_and_resolve_mounts() {
+        let temp_dir = tempfile::tempdir().expect("should create temp dir");
+        let test_dir = temp_dir.path();
+        let toolchains = test_dir.join("toolchains");
+        std::fs::create_dir_all(toolchains.join("node-22")).expect("should create dir");
+        std::fs::create_dir_all(test_dir.join("secret")).expect("should create dir");
+        std::os::unix::fs::symlink(test_dir.join("secret"), toolchains.join("escape"))
+            .expect("should create symlink");
+
+        let config = format!(
+            r#"
+workspace: build/workspace
+build:
+  image: node:22
+  mounts:
+    - {toolchains}/node-22:/opt/node
+    - source: {toolchains}/node-22
+      target: /opt/cache
+      read_only: false
+  script:
+    - /opt/node/bin/npm ci
+        "#,
+            toolchains = toolchains.display()
+        );
+        let mut parser_config =
+            ParserConfig::parse_str(config.as_str()).expect("parsing should suceed");
+        assert_eq!(parser_config.workspace, Some("build/workspace".to_string()));
+        let node = format!("{}/node-22", toolchains.display());
+        assert_eq!(
+            parser_config.jobs[0].mounts,
+            vec![
+                Mount::new_with_params(node.clone(), "/opt/node".to_string()),
+                Mount {
+                    read_only: false,
+                    ..Mount::new_with_params(node.clone(), "/opt/cache".to_string())
+                },
+            ]
+        );
+
+        assert!(matches!(
+            ParserConfig::parse_str(config.as_str()).and_then(|mut c| c.resolve_mounts(&[])),
+            Err(MountError(source, _)) if source == node
+        ));
+        let roots = vec![toolchains.display().to_string()];
+        parser_config
+            .resolve_mounts(&roots)
+            .expect("mounts should be allowed");
+        assert_eq!(
+            parser_config.jobs[0].mounts[0].source,
+            std::fs::canonicalize(toolchains.join("node-22"))
+                .expect("should canonicalize")
+                .display()
+                .to_string()
+        );
+
+        // Symlinks are followed before the source is checked
+        let escape = config.replace("node-22:/opt/node", "escape:/opt/node");
+        let mut parser_config =
+            ParserConfig::parse_str(escape.as_str()).expect("parsing should suceed");
+        assert!(parser_config.resolve_mounts(&roots).is_err());
+
+        let inside_workspace = config.replace("/opt/cache", "/workspace/cache");
+        let mut parser_config =
+            ParserConfig::parse_str(inside_workspace.as_str()).expect("parsing should suceed");
+        assert!(matches!(
+            parser_config.resolve_mounts(&roots),
+            Err(MountError(target, _)) if target == "/workspace/cache"
+        ));
+
+        let injected = config.replace("/opt/cache", "/opt/cache,readonly=false");
+        let mut parser_config =
+            ParserConfig::parse_str(injected.as_str()).expect("parsing should suceed");
+        assert!(matches!(
+            parser_config.resolve_mounts(&roots),
+            Err(MountError(target, _)) if target == "/opt/cache,readonly=false"
+        ));
+
+        // The workspace is relative to the file setting it
+        let sources = HashMap::from([("workspace".to_string(), "ci/pipeline.yml".to_string())]);
+        let parser_config = ParserConfig::load_yaml(config.as_str())
+            .and_then(|config| ParserConfig::parse_mapping(config, &sources))
+            .expect("parsing should suceed");
+        assert_eq!(
+            parser_config.workspace,
+            Some("ci/build/workspace".to_string())
+        );
+
+        let invalid = config.replace(":/opt/node", ":/opt/node:rx");
+        assert!(matches!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(e)) if e.starts_with("invalid mount")
+        ));
+        let invalid = config.replace("target: /opt/cache", "path: /opt/cache");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "mount should have a source and a target".to_string()
+            ))
+        );
+
+        let workspace = test_dir.join("nested/../workspace");
+        let resolved = Pipeline::resolve_workspace(workspace.display().to_string().as_str())
+            .expect("workspace should resolve");
+        assert_eq!(
+            resolved,
+            std::fs::canonicalize(test_dir.join("workspace"))
+                .expect("should exist")
+                .display()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_services() {
+        let config = r#"
+variables:
+  PG_VERSION: "16"
+default:
+  services:
+    - redis:7
+integration:
+  image: python:3.11
+  services:
+    - registry.local:5000/tools/mock-server
+    - image: postgres:${PG_VERSION}
+      alias: db
+      command: ["postgres", "-c", "fsync=off"]
+      variables:
+        POSTGRES_PASSWORD: test
+  script:
+    - pytest tests/integration
+unit:
+  image: python:3.11
+  script:
+    - pytest tests/unit
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        let job = parser_config.substitute_job_config(&parser_config.jobs[0], &[]);
+        assert_eq!(
+            job.services,
+            vec![
+                Service {
+                    image: "registry.local:5000/tools/mock-server".to_string(),
+                    alias: "registry.local-5000-tools-mock-server".to_string(),
+                    ..Default::default()
+                },
+                Service {
+                    image: "postgres:16".to_string(),
+                    alias: "db".to_string(),
+                    command: vec![
+                        "postgres".to_string(),
+                        "-c".to_string(),
+                        "fsync=off".to_string()
                     ],
-                    stage: None,
-                    needs: None,
-                    artifacts: None,
-                }],
-                None
-            )
+                    variables: vec![Variable {
+                        key: "POSTGRES_PASSWORD".to_string(),
+                        value: "test".to_string(),
+                    }],
+                },
+            ]
+        );
+        assert_eq!(
+            parser_config.jobs[1].services,
+            vec![Service {
+                image: "redis:7".to_string(),
+                alias: "redis".to_string(),
+                ..Default::default()
+            }]
+        );
+
+        let invalid = config.replace("alias: db", "alias: redis");
+        let invalid = invalid.replace("registry.local:5000/tools/mock-server", "redis:6");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "integration has more than one service with alias redis".to_string()
+            ))
+        );
+
+        let invalid = config.replace("alias: db", "alias: my db");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(
+                "service alias my db should be a valid host name".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_cache() {
+        let parser_config = ParserConfig::parse_from_file("samples/simple-cache.yml")
+            .expect("parsing should suceed");
+        assert_eq!(
+            parser_config.jobs[0].cache,
+            vec![CacheConfig {
+                key: CacheKey::Files {
+                    files: vec!["requirements.txt".to_string()],
+                    prefix: Some("pip".to_string()),
+                },
+                paths: vec![".cache/pip".to_string()],
+                policy: CachePolicy::PullPush,
+                fallback_keys: vec!["pip-default".to_string()],
+            }]
+        );
+        assert_eq!(
+            parser_config.jobs[1].cache,
+            vec![CacheConfig {
+                key: CacheKey::Literal("lint".to_string()),
+                paths: vec![".cache/pip".to_string()],
+                policy: CachePolicy::Pull,
+                fallback_keys: vec![],
+            }]
+        );
+
+        let config = r#"
+variables:
+  PYTHON: "3.12"
+build:
+  image: python:${PYTHON}
+  cache:
+    - key: pip-${PYTHON}
+      paths:
+        - .cache/pip
+    - paths:
+        - node_modules
+      policy: always
+  script:
+    - pip install build
+        "#;
+        assert_eq!(
+            ParserConfig::parse_str(config),
+            Err(ParsingError(
+                "cache policy should be one of pull, push or pull-push".to_string()
+            ))
+        );
+        let parser_config = ParserConfig::parse_str(&config.replace("policy: always", ""))
+            .expect("parsing should suceed");
+        let job = parser_config.substitute_job_config(&parser_config.jobs[0], &[]);
+        assert_eq!(job.cache[0].key, CacheKey::Literal("pip-3.12".to_string()));
+        assert_eq!(job.cache[1].key, CacheKey::default());
+    }
+
+    #[test]
+    fn test_parse_coverage() {
+        let config = r#"
+test:
+  image: python:3.11
+  script:
+    - pytest --cov
+  coverage: '/TOTAL.+ ([0-9]{1,3}%)/'
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(
+            parser_config.jobs[0].coverage,
+            Some("/TOTAL.+ ([0-9]{1,3}%)/".to_string())
+        );
+
+        let invalid = config.replace("{1,3}", "{3,1}");
+        assert!(matches!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError(e)) if e.starts_with("coverage should be a valid regex")
+        ));
+        let invalid = config.replace("'/TOTAL.+ ([0-9]{1,3}%)/'", "true");
+        assert_eq!(
+            ParserConfig::parse_str(invalid.as_str()),
+            Err(ParsingError("coverage should be a string".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_cache_result() {
+        let config = r#"
+lint:
+  image: python:3.11
+  cache_result: true
+  inputs:
+    - "src/**/*.py"
+    - pyproject.toml
+  script:
+    - ruff check
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert!(parser_config.jobs[0].cache_result);
+        assert_eq!(
+            parser_config.jobs[0].inputs,
+            vec!["src/**/*.py".to_string(), "pyproject.toml".to_string()]
+        );
+
+        assert_eq!(
+            ParserConfig::parse_str(&config.replace("true", "yes please")),
+            Err(ParsingError("cache_result should be a boolean".to_string()))
+        );
+        assert_eq!(
+            ParserConfig::parse_str(&config.replace("inputs", "unused")),
+            Err(ParsingError("cache_result requires inputs".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_cancelled_jobs() {
+        let config = r#"
+stages:
+  - test
+  - cleanup
+test:
+  image: python:3.11
+  stage: test
+  script:
+    - pytest
+cleanup:
+  image: alpine
+  stage: cleanup
+  when: always
+  script:
+    - ./cleanup.sh
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(parser_config.jobs[0].when, JobWhen::OnSuccess);
+        assert_eq!(parser_config.jobs[1].when, JobWhen::Always);
+        assert_eq!(
+            ParserConfig::parse_str(&config.replace("when: always", "when: manual")),
+            Err(ParsingError(
+                "when should be one of on_success or always".to_string()
+            ))
         );
+
+        let cancellation = Cancellation::new_with_params("1-1".to_string());
+        assert!(!cancellation.cancel());
+        assert!(cancellation.clone().cancel());
+        assert!(cancellation.is_cancelled());
+        assert_eq!(cancellation.label(), "pipeline-runner.run=1-1");
+
+        // Pending jobs return before anything is started
+        let rt = Runtime::new().expect("runtime should start");
+        let report = rt.block_on(Pipeline::execute_job(
+            parser_config.jobs[0].clone(),
+            Pipeline::get_artifact_manager("1-1"),
+            CacheManager::new_with_params(
+                DEFAULT_WORKSPACE.to_string(),
+                Pipeline::get_cache_location(),
+            ),
+            vec![],
+            cancellation,
+        ));
+        assert_eq!(report, None);
     }
 
     #[test]
@@ -429,7 +3455,7 @@ build-job:
         fn create_job_with_deps(job_name: String, deps: Option<Vec<String>>) -> JobConfig {
             JobConfig {
                 name: job_name,
-                image: "python:3.11".to_string(),
+                image: ImageConfig::new_with_params("python:3.11".to_string()),
                 script: vec![
                     "echo \"Building application...\"".to_string(),
                     "python --version".to_string(),
@@ -437,8 +3463,13 @@ build-job:
                     "echo \"Build complete!\"".to_string(),
                 ],
                 stage: None,
-                needs: deps,
-                artifacts: Some(vec![]),
+                needs: deps.map(|deps| {
+                    deps.into_iter()
+                        .map(|dep| Need::new_with_params(dep, true, false))
+                        .collect()
+                }),
+                artifacts: Some(ArtifactsConfig::default()),
+                ..Default::default()
             }
         }
 
@@ -452,11 +3483,56 @@ build-job:
         let jobs = vec![&integration_test_job, &build_job, &unit_test_job];
 
         assert_eq!(
-            Pipeline::get_execution_order(jobs),
+            Pipeline::get_execution_order(
+                &ParserConfig::new_with_params(vec![], None, vec![]),
+                jobs
+            ),
             vec![
                 vec![&build_job],
                 vec![&integration_test_job, &unit_test_job]
             ]
         );
     }
+
+    #[test]
+    fn test_execution_order_without_stages() {
+        let config = r#"
+build:
+  image: python:3.11
+  script:
+    - python -m build
+
+lint:
+  image: python:3.11
+  script:
+    - ruff check
+
+test:
+  image: python:3.11
+  needs:
+    - build
+  script:
+    - pytest
+
+deploy:
+  image: alpine
+  needs:
+    - test
+    - lint
+  script:
+    - ./deploy.sh
+        "#;
+        let parser_config = ParserConfig::parse_str(config).expect("parsing should suceed");
+        assert_eq!(parser_config.stages, None);
+
+        let order: Vec<Vec<&str>> =
+            Pipeline::get_execution_order(&parser_config, parser_config.jobs.iter().collect())
+                .iter()
+                .map(|jobs| jobs.iter().map(|j| j.name.as_str()).collect())
+                .collect();
+        assert_eq!(
+            order,
+            vec![vec!["build", "lint"], vec!["test"], vec!["deploy"]]
+        );
+    }
 }
@@ -0,0 +1,476 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::job::Variable;
+
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer, XmlVersion};
+use regex::Regex;
+use serde::Serialize;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TestStatus {
+    Passed,
+    // Holds the failure message. Errors are counted as failures
+    Failed(String),
+    Skipped,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TestCase {
+    pub classname: String,
+    pub name: String,
+    pub time: f64,
+    pub status: TestStatus,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TestSuite {
+    pub name: String,
+    pub cases: Vec<TestCase>,
+}
+
+impl TestSuite {
+    pub fn test_counts(&self) -> TestCounts {
+        let mut counts = TestCounts::default();
+        for case in self.cases.iter() {
+            match case.status {
+                TestStatus::Passed => counts.passed += 1,
+                TestStatus::Failed(_) => counts.failed += 1,
+                TestStatus::Skipped => counts.skipped += 1,
+            }
+        }
+        counts
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize)]
+pub struct TestCounts {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+impl TestCounts {
+    pub fn total(&self) -> usize {
+        self.passed + self.failed + self.skipped
+    }
+
+    pub fn add(&mut self, other: TestCounts) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+    }
+}
+
+// What a job reported besides its artifacts
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct JobReport {
+    pub job: String,
+    pub test_suites: Vec<TestSuite>,
+    // Percentage extracted from the job output with the job's coverage regex
+    pub coverage: Option<f64>,
+}
+
+impl JobReport {
+    pub fn new_with_params(job: String) -> Self {
+        Self {
+            job,
+            ..Default::default()
+        }
+    }
+
+    pub fn test_counts(&self) -> TestCounts {
+        let mut counts = TestCounts::default();
+        for suite in self.test_suites.iter() {
+            counts.add(suite.test_counts());
+        }
+        counts
+    }
+
+    pub fn failed_tests(&self) -> Vec<&TestCase> {
+        self.test_suites
+            .iter()
+            .flat_map(|s| s.cases.iter())
+            .filter(|case| matches!(case.status, TestStatus::Failed(_)))
+            .collect()
+    }
+}
+
+fn get_attribute(element: &BytesStart, key: &str) -> Result<Option<String>, String> {
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(|e| e.to_string())?;
+        if attribute.key.as_ref() == key {
+            let value = attribute
+                .normalized_value(XmlVersion::Implicit1_0)
+                .map_err(|e| e.to_string())?;
+            return Ok(Some(value.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parses a JUnit XML report. Both a single `<testsuite>` and `<testsuites>`
+// wrapping several of them are supported
+pub fn parse_junit(xml: &str) -> Result<Vec<TestSuite>, String> {
+    let mut reader = Reader::from_str(xml);
+    let mut suites: Vec<TestSuite> = vec![];
+    let mut case: Option<TestCase> = None;
+
+    loop {
+        let event = reader.read_event().map_err(|e| e.to_string())?;
+        let (element, is_empty) = match event {
+            Event::Start(ref element) => (element, false),
+            Event::Empty(ref element) => (element, true),
+            Event::End(ref element) if element.name().as_ref() == "testcase" => {
+                suites
+                    .last_mut()
+                    .ok_or("testcase outside of a testsuite")?
+                    .cases
+                    .extend(case.take());
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+
+        match element.name().as_ref() {
+            "testsuite" => suites.push(TestSuite {
+                name: get_attribute(element, "name")?.unwrap_or_default(),
+                cases: vec![],
+            }),
+            "testcase" => {
+                let new_case = TestCase {
+                    classname: get_attribute(element, "classname")?.unwrap_or_default(),
+                    name: get_attribute(element, "name")?.unwrap_or_default(),
+                    time: get_attribute(element, "time")?
+                        .and_then(|time| time.parse().ok())
+                        .unwrap_or_default(),
+                    status: TestStatus::Passed,
+                };
+                if is_empty {
+                    suites
+                        .last_mut()
+                        .ok_or("testcase outside of a testsuite")?
+                        .cases
+                        .push(new_case);
+                } else {
+                    case = Some(new_case);
+                }
+            }
+            "failure" | "error" => {
+                if let Some(ref mut case) = case {
+                    let message = get_attribute(element, "message")?;
+                    case.status = TestStatus::Failed(message.unwrap_or_default());
+                }
+            }
+            "skipped" => {
+                if let Some(ref mut case) = case {
+                    case.status = TestStatus::Skipped;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(suites)
+}
+
+// Writes all suites of all jobs into a single report. Suites are prefixed with
+// the name of the job that reported them
+pub fn write_junit(path: &Path, reports: &[JobReport]) -> std::io::Result<()> {
+    let mut writer = Writer::new_with_indent(BufWriter::new(File::create(path)?), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+
+    let mut counts = TestCounts::default();
+    for report in reports {
+        counts.add(report.test_counts());
+    }
+    let mut testsuites = BytesStart::new("testsuites");
+    testsuites.push_attribute(("tests", counts.total().to_string().as_str()));
+    testsuites.push_attribute(("failures", counts.failed.to_string().as_str()));
+    testsuites.push_attribute(("skipped", counts.skipped.to_string().as_str()));
+    writer.write_event(Event::Start(testsuites))?;
+
+    for report in reports {
+        for suite in report.test_suites.iter() {
+            let counts = suite.test_counts();
+            let mut testsuite = BytesStart::new("testsuite");
+            testsuite.push_attribute(("name", format!("{}: {}", report.job, suite.name).as_str()));
+            testsuite.push_attribute(("tests", counts.total().to_string().as_str()));
+            testsuite.push_attribute(("failures", counts.failed.to_string().as_str()));
+            testsuite.push_attribute(("skipped", counts.skipped.to_string().as_str()));
+            writer.write_event(Event::Start(testsuite))?;
+
+            for case in suite.cases.iter() {
+                let mut testcase = BytesStart::new("testcase");
+                testcase.push_attribute(("classname", case.classname.as_str()));
+                testcase.push_attribute(("name", case.name.as_str()));
+                testcase.push_attribute(("time", case.time.to_string().as_str()));
+                match case.status {
+                    TestStatus::Passed => writer.write_event(Event::Empty(testcase))?,
+                    TestStatus::Failed(ref message) => {
+                        writer.write_event(Event::Start(testcase))?;
+                        let mut failure = BytesStart::new("failure");
+                        failure.push_attribute(("message", message.as_str()));
+                        writer.write_event(Event::Empty(failure))?;
+                        writer.write_event(Event::End(BytesEnd::new("testcase")))?;
+                    }
+                    TestStatus::Skipped => {
+                        writer.write_event(Event::Start(testcase))?;
+                        writer.write_event(Event::Empty(BytesStart::new("skipped")))?;
+                        writer.write_event(Event::End(BytesEnd::new("testcase")))?;
+                    }
+                }
+            }
+
+            writer.write_event(Event::End(BytesEnd::new("testsuite")))?;
+        }
+    }
+
+    writer.write_event(Event::End(BytesEnd::new("testsuites")))?;
+    writer
+        .into_inner()
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()
+}
+
+// Coverage regexes may be written GitLab style, wrapped in slashes
+pub fn coverage_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    let pattern = pattern
+        .strip_prefix('/')
+        .and_then(|pattern| pattern.strip_suffix('/'))
+        .unwrap_or(pattern);
+    Regex::new(pattern)
+}
+
+// Returns the number in the first capture group of the match, or in the whole
+// match if the regex has no groups
+pub fn extract_coverage(regex: &Regex, line: &str) -> Option<f64> {
+    let captures = regex.captures(line)?;
+    let matched = captures.get(1).or(captures.get(0))?.as_str();
+
+    let start = matched.find(|c: char| c.is_ascii_digit())?;
+    let number = &matched[start..];
+    let end = number
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(number.len());
+    number[..end].trim_end_matches('.').parse().ok()
+}
+
+// The average over all jobs that reported a coverage, like GitLab does for
+// pipelines
+pub fn pipeline_coverage(reports: &[JobReport]) -> Option<f64> {
+    let coverages: Vec<f64> = reports.iter().filter_map(|r| r.coverage).collect();
+    if coverages.is_empty() {
+        return None;
+    }
+    Some(coverages.iter().sum::<f64>() / coverages.len() as f64)
+}
+
+#[derive(Serialize)]
+struct JobSummary<'a> {
+    job: &'a str,
+    coverage: Option<f64>,
+    tests: Option<TestCounts>,
+}
+
+#[derive(Serialize)]
+struct PipelineSummary<'a> {
+    coverage: Option<f64>,
+    jobs: Vec<JobSummary<'a>>,
+}
+
+// Writes the coverage and test counts of every job as JSON, for tools that
+// don't want to parse the output of the run
+pub fn write_summary(path: &Path, reports: &[JobReport]) -> std::io::Result<()> {
+    let summary = PipelineSummary {
+        coverage: pipeline_coverage(reports),
+        jobs: reports
+            .iter()
+            .map(|report| JobSummary {
+                job: report.job.as_str(),
+                coverage: report.coverage,
+                tests: (!report.test_suites.is_empty()).then(|| report.test_counts()),
+            })
+            .collect(),
+    };
+
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut file, &summary).map_err(std::io::Error::other)?;
+    file.flush()
+}
+
+// Parses a dotenv report: one KEY=VALUE per line. Blank lines and lines
+// starting with `#` are ignored, and values may be wrapped in quotes
+pub fn parse_dotenv(contents: &str) -> Result<Vec<Variable>, String> {
+    let mut variables: Vec<Variable> = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {} is not KEY=VALUE", i + 1));
+        };
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("line {} has an invalid key {:?}", i + 1, key));
+        }
+
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(*quote)
+                    .and_then(|value| value.strip_suffix(*quote))
+            })
+            .unwrap_or(value);
+
+        variables.retain(|v| v.key != key);
+        variables.push(Variable {
+            key: key.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    Ok(variables)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_and_write_junit() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<testsuites>
+  <testsuite name="pytest" tests="4">
+    <testcase classname="tests.test_app" name="test_ok" time="0.01"/>
+    <testcase classname="tests.test_app" name="test_broken" time="0.20">
+      <failure message="assert 1 == 2">def test_broken(): &lt;...&gt;</failure>
+    </testcase>
+    <testcase classname="tests.test_app" name="test_crash">
+      <error message="ZeroDivisionError &amp; more"/>
+    </testcase>
+    <testcase classname="tests.test_app" name="test_later">
+      <skipped message="not yet"/>
+    </testcase>
+  </testsuite>
+</testsuites>"#;
+
+        let report = JobReport {
+            job: "unit-tests".to_string(),
+            test_suites: parse_junit(xml).expect("report should parse"),
+            ..Default::default()
+        };
+        assert_eq!(
+            report.test_counts(),
+            TestCounts {
+                passed: 1,
+                failed: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(
+            report
+                .failed_tests()
+                .iter()
+                .map(|case| case.status.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                TestStatus::Failed("assert 1 == 2".to_string()),
+                TestStatus::Failed("ZeroDivisionError & more".to_string()),
+            ]
+        );
+
+        let file = tempfile::NamedTempFile::new().expect("should create temp file");
+        let path = file.path();
+        write_junit(path, &[report.clone(), report.clone()]).expect("report should be written");
+        let merged = parse_junit(
+            std::fs::read_to_string(path)
+                .expect("report should exist")
+                .as_str(),
+        )
+        .expect("merged report should parse");
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].name, "unit-tests: pytest");
+        assert_eq!(merged[0].cases, report.test_suites[0].cases);
+
+        assert!(parse_junit("<testsuite><testcase></testsuite>").is_err());
+    }
+
+    #[test]
+    fn test_parse_dotenv() {
+        let contents = "# build info\nVERSION=1.2.3\n\n  IMAGE_TAG = \"v1.2.3\"\nEMPTY=\nURL=http://x?a=b\nVERSION='1.2.4'\n";
+        let variables = parse_dotenv(contents).expect("dotenv should parse");
+        assert_eq!(
+            variables
+                .iter()
+                .map(|v| (v.key.as_str(), v.value.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("IMAGE_TAG", "v1.2.3"),
+                ("EMPTY", ""),
+                ("URL", "http://x?a=b"),
+                ("VERSION", "1.2.4"),
+            ]
+        );
+
+        assert!(parse_dotenv("VERSION").is_err());
+        assert!(parse_dotenv("MY-VAR=1").is_err());
+    }
+
+    #[test]
+    fn test_extract_coverage() {
+        let regex = coverage_regex(r"/TOTAL.+ (\d+%)/").expect("regex should compile");
+        assert_eq!(
+            extract_coverage(&regex, "TOTAL    120    18    85%"),
+            Some(85.0)
+        );
+        assert_eq!(extract_coverage(&regex, "app.py    20    2    90%"), None);
+
+        // Without groups the number is taken from the whole match
+        let regex = coverage_regex(r"Coverage: \d+\.\d+%").expect("regex should compile");
+        assert_eq!(
+            extract_coverage(&regex, "Lines Coverage: 73.25% of 400."),
+            Some(73.25)
+        );
+        assert!(coverage_regex("/(unclosed/").is_err());
+
+        let reports = vec![
+            JobReport {
+                job: "unit".to_string(),
+                coverage: Some(80.0),
+                ..Default::default()
+            },
+            JobReport::new_with_params("lint".to_string()),
+            JobReport {
+                job: "integration".to_string(),
+                coverage: Some(60.0),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(pipeline_coverage(&reports), Some(70.0));
+        assert_eq!(pipeline_coverage(&reports[1..2]), None);
+
+        let file = tempfile::NamedTempFile::new().expect("should create temp file");
+        let path = file.path();
+        write_summary(path, &reports).expect("summary should be written");
+        let summary: serde_json::Value = serde_json::from_str(
+            std::fs::read_to_string(path)
+                .expect("summary should exist")
+                .as_str(),
+        )
+        .expect("summary should be JSON");
+        assert_eq!(summary["coverage"], 70.0);
+        assert_eq!(summary["jobs"][0]["job"], "unit");
+        assert_eq!(summary["jobs"][1]["coverage"], serde_json::Value::Null);
+    }
+}
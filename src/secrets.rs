@@ -0,0 +1,173 @@
+use crate::error::PipelineError;
+use crate::error::PipelineError::SecretError;
+
+const MASK: &str = "[MASKED]";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SecretFile {
+    pub key: String,
+    pub path: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Secret {
+    pub key: String,
+    pub value: String,
+}
+
+impl SecretFile {
+    pub fn new_with_params(key: String, path: String) -> Self {
+        Self { key, path }
+    }
+
+    // The whole file is the secret value. A single trailing newline is dropped
+    // since most editors add one.
+    pub fn read(&self) -> Result<Secret, PipelineError> {
+        let value = std::fs::read_to_string(self.path.as_str())
+            .map_err(|e| SecretError(self.key.clone(), e.to_string()))?;
+        let value = value
+            .strip_suffix('\n')
+            .map(|v| v.strip_suffix('\r').unwrap_or(v))
+            .unwrap_or(value.as_str())
+            .to_string();
+
+        Ok(Secret {
+            key: self.key.clone(),
+            value,
+        })
+    }
+}
+
+// Reads a `KEY=VALUE` per line secrets file. Empty lines and lines starting with
+// '#' are ignored
+pub fn load_secrets_file(file_path: &str) -> Result<Vec<Secret>, PipelineError> {
+    let contents = std::fs::read_to_string(file_path)
+        .map_err(|e| SecretError(file_path.to_string(), e.to_string()))?;
+
+    let mut secrets = vec![];
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(SecretError(
+                file_path.to_string(),
+                format!("line {} should be of the form KEY=VALUE", line_no + 1),
+            ));
+        };
+        secrets.push(Secret {
+            key: key.trim().to_string(),
+            value: value.trim().to_string(),
+        });
+    }
+
+    Ok(secrets)
+}
+
+// Like GitLab, shorter values aren't masked since they would hide unrelated
+// parts of the output
+const MIN_MASKED_LEN: usize = 8;
+
+// The parts of a secret that get masked. Output is masked line by line, so each
+// line of a multi-line secret like a key file is masked on its own. PEM armor
+// lines like `-----END RSA PRIVATE KEY-----` are the same in every key and are
+// left alone
+fn masked_values(secret: &Secret) -> impl Iterator<Item = &str> {
+    secret
+        .value
+        .lines()
+        .map(str::trim)
+        .filter(|v| v.len() >= MIN_MASKED_LEN && !v.starts_with("-----"))
+}
+
+// Secrets with nothing left to mask would show up in the output as is
+pub fn warn_unmasked_secrets(secrets: &[Secret]) {
+    for secret in secrets {
+        if masked_values(secret).next().is_none() {
+            println!(
+                "Secret {} is shorter than {} characters and will not be masked",
+                secret.key, MIN_MASKED_LEN
+            );
+        }
+    }
+}
+
+// Replaces every occurrence of a secret value in `line`. Longer values are
+// masked first so a secret containing another one is not partially revealed
+pub fn mask_secrets(line: &str, secrets: &[Secret]) -> String {
+    let mut values: Vec<&str> = secrets.iter().flat_map(masked_values).collect();
+    values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+
+    let mut masked = line.to_string();
+    for value in values {
+        masked = masked.replace(value, MASK);
+    }
+
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_mask_secrets() {
+        let secrets = vec![
+            Secret {
+                key: "TOKEN".to_string(),
+                value: "abcdefgh".to_string(),
+            },
+            Secret {
+                key: "LONG_TOKEN".to_string(),
+                value: "abcdefghijkl".to_string(),
+            },
+            Secret {
+                key: "EMPTY".to_string(),
+                value: "".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            mask_secrets("token=abcdefghijkl other=abcdefgh", &secrets),
+            "token=[MASKED] other=[MASKED]"
+        );
+        assert_eq!(mask_secrets("nothing here", &secrets), "nothing here");
+
+        let key_file = vec![Secret {
+            key: "SSH_KEY".to_string(),
+            value: "-----BEGIN KEY-----\r\nc2VjcmV0\r\n-----END KEY-----".to_string(),
+        }];
+        assert_eq!(mask_secrets("key c2VjcmV0", &key_file), "key [MASKED]");
+        assert_eq!(
+            mask_secrets("-----END KEY-----", &key_file),
+            "-----END KEY-----"
+        );
+    }
+
+    #[test]
+    fn test_short_secrets_are_not_masked() {
+        let secrets = vec![
+            Secret {
+                key: "FLAG".to_string(),
+                value: "1".to_string(),
+            },
+            Secret {
+                key: "CREDENTIALS".to_string(),
+                value: "{\n  \"token\": \"s3cr3t-value\"\n}".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            mask_secrets("{ \"count\": 1 }", &secrets),
+            "{ \"count\": 1 }"
+        );
+        assert_eq!(
+            mask_secrets("  \"token\": \"s3cr3t-value\"", &secrets),
+            "  [MASKED]"
+        );
+        assert_eq!(masked_values(&secrets[0]).next(), None);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::error::PipelineError;
+use crate::error::PipelineError::ServiceError;
+use crate::job::{JobConfig, Service};
+
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A service that isn't ready in time fails the job, which would otherwise fail
+// later on with a less obvious error
+const SERVICE_READY_TIMEOUT: Duration = Duration::from_secs(30);
+const SERVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn docker(args: &[&str]) -> Result<String, String> {
+    let capture = subprocess::Exec::cmd("docker")
+        .args(args)
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Merge)
+        .capture()
+        .map_err(|e| e.to_string())?;
+    if !capture.success() {
+        return Err(capture.stdout_str().trim().to_string());
+    }
+    Ok(capture.stdout_str().trim().to_string())
+}
+
+// The services of a job and the network connecting them to it. Everything is
+// removed when this is dropped, so services never outlive their job, whether
+// it succeeded, failed or returned early with an error
+pub struct JobServices {
+    network: String,
+    containers: Vec<String>,
+}
+
+impl JobServices {
+    // Starts the services of `job` on a network of their own and waits until
+    // they are ready. The job container joins the network to reach them by
+    // alias. Everything is labelled with `label` so cancelling the run finds it
+    pub fn start(
+        job: &JobConfig,
+        container_name: &str,
+        label: &str,
+    ) -> Result<Self, PipelineError> {
+        let network = format!("{}-network", container_name);
+        docker(&["network", "create", "--label", label, network.as_str()])
+            .map_err(|e| ServiceError(job.name.clone(), e))?;
+        let mut services = Self {
+            network,
+            containers: vec![],
+        };
+
+        for service in job.services.iter() {
+            let service_name = format!("{}-{}", container_name, service.alias);
+            // Tracked before it is started, so a container docker created but
+            // failed to start is removed too
+            services.containers.push(service_name.clone());
+
+            let mut cmd = vec![
+                "run".to_string(),
+                "--detach".to_string(),
+                "--name".to_string(),
+                service_name,
+                "--label".to_string(),
+                label.to_string(),
+                "--network".to_string(),
+                services.network.clone(),
+                "--network-alias".to_string(),
+                service.alias.clone(),
+            ];
+            // Services see the job variables too, so e.g. database credentials
+            // only need to be defined once
+            for var in job.variables.iter().chain(service.variables.iter()) {
+                cmd.push("-e".to_string());
+                cmd.push(format!("{}={}", var.key, var.value));
+            }
+            cmd.push(service.image.clone());
+            cmd.extend(service.command.iter().cloned());
+
+            let args: Vec<&str> = cmd.iter().map(String::as_str).collect();
+            docker(&args).map_err(|e| ServiceError(service.alias.clone(), e))?;
+            println!("[{}] SERVICE: started {}", job.name, service.alias);
+        }
+
+        for (service, container) in job.services.iter().zip(services.containers.iter()) {
+            Self::wait_until_ready(job, service, container.as_str())?;
+        }
+        Ok(services)
+    }
+
+    pub fn network(&self) -> &str {
+        self.network.as_str()
+    }
+
+    // A service is ready once its healthcheck passes and all TCP ports its image
+    // exposes accept connections. Images without either are considered ready as
+    // soon as they are running
+    fn wait_until_ready(
+        job: &JobConfig,
+        service: &Service,
+        container: &str,
+    ) -> Result<(), PipelineError> {
+        let service_error = |e: String| ServiceError(service.alias.clone(), e);
+        let deadline = Instant::now() + SERVICE_READY_TIMEOUT;
+        loop {
+            let state = docker(&[
+                "inspect",
+                "--format",
+                "{{.State.Status}} {{if .State.Health}}{{.State.Health.Status}}{{end}}",
+                container,
+            ])
+            .map_err(service_error)?;
+            let mut state = state.split_whitespace();
+            let status = state.next().unwrap_or_default();
+            let health = state.next();
+
+            match (status, health) {
+                ("running", None | Some("healthy"))
+                    if Self::ports_open(container).map_err(service_error)? =>
+                {
+                    println!("[{}] SERVICE: {} is ready", job.name, service.alias);
+                    return Ok(());
+                }
+                ("exited" | "dead", _) | (_, Some("unhealthy")) => {
+                    Self::print_logs(job, service, container);
+                    return Err(service_error(format!(
+                        "{} before it was ready",
+                        health.unwrap_or(status)
+                    )));
+                }
+                _ => {}
+            }
+
+            if Instant::now() >= deadline {
+                Self::print_logs(job, service, container);
+                return Err(service_error(format!(
+                    "not ready after {:?}",
+                    SERVICE_READY_TIMEOUT
+                )));
+            }
+            thread::sleep(SERVICE_POLL_INTERVAL);
+        }
+    }
+
+    // Connects to the exposed TCP ports through the container's address on the
+    // job network
+    fn ports_open(container: &str) -> Result<bool, String> {
+        let ports = docker(&[
+            "inspect",
+            "--format",
+            concat!(
+                "{{range .NetworkSettings.Networks}}{{.IPAddress}} {{end}}|",
+                "{{range $port, $_ := .Config.ExposedPorts}}{{$port}} {{end}}"
+            ),
+            container,
+        ])?;
+        let (addresses, ports) = ports.split_once('|').unwrap_or_default();
+        let ports: Vec<u16> = ports
+            .split_whitespace()
+            .filter_map(|port| port.strip_suffix("/tcp")?.parse().ok())
+            .collect();
+        if ports.is_empty() {
+            return Ok(true);
+        }
+        let Some(address) = addresses
+            .split_whitespace()
+            .find_map(|address| address.parse::<IpAddr>().ok())
+        else {
+            return Ok(false);
+        };
+
+        Ok(ports.iter().all(|port| {
+            TcpStream::connect_timeout(&SocketAddr::new(address, *port), SERVICE_POLL_INTERVAL)
+                .is_ok()
+        }))
+    }
+
+    fn print_logs(job: &JobConfig, service: &Service, container: &str) {
+        let logs = docker(&["logs", "--tail", "20", container]).unwrap_or_else(|e| e);
+        for line in logs.lines() {
+            println!("[{}] {} | {}", job.name, service.alias, line);
+        }
+    }
+}
+
+impl Drop for JobServices {
+    fn drop(&mut self) {
+        if !self.containers.is_empty() {
+            let mut args = vec!["rm", "--force", "--volumes"];
+            args.extend(self.containers.iter().map(String::as_str));
+            if let Err(e) = docker(&args) {
+                println!("Services could not be removed: {}", e);
+            }
+        }
+        if let Err(e) = docker(&["network", "rm", self.network.as_str()]) {
+            println!("Network {} could not be removed: {}", self.network, e);
+        }
+    }
+}